@@ -0,0 +1,97 @@
+-- Representative report per homeserver and day, which all higher aggregates
+-- are derived from
+CREATE TABLE IF NOT EXISTS homeserver_daily
+(
+    day date NOT NULL,
+    homeserver TEXT NOT NULL,
+    report_id BIGINT NOT NULL,
+    local_timestamp timestamp with time zone,
+    uptime_seconds BIGINT,
+    total_users BIGINT,
+    total_nonbridged_users BIGINT,
+    total_room_count BIGINT,
+    daily_active_users BIGINT,
+    daily_active_rooms BIGINT,
+    daily_messages BIGINT,
+    daily_sent_messages BIGINT,
+    daily_active_e2ee_rooms BIGINT,
+    daily_e2ee_messages BIGINT,
+    daily_sent_e2ee_messages BIGINT,
+    monthly_active_users BIGINT,
+    r30_users_all BIGINT,
+    r30_users_android BIGINT,
+    r30_users_ios BIGINT,
+    r30_users_electron BIGINT,
+    r30_users_web BIGINT,
+    r30v2_users_all BIGINT,
+    r30v2_users_android BIGINT,
+    r30v2_users_ios BIGINT,
+    r30v2_users_electron BIGINT,
+    r30v2_users_web BIGINT,
+    cpu_average BIGINT,
+    memory_rss BIGINT,
+    cache_factor DOUBLE PRECISION,
+    event_cache_size BIGINT,
+    user_agent TEXT,
+    daily_user_type_native BIGINT,
+    daily_user_type_bridged BIGINT,
+    daily_user_type_guest BIGINT,
+    python_version TEXT,
+    database_engine TEXT,
+    database_server_version TEXT,
+    server_context TEXT,
+    log_level TEXT,
+    PRIMARY KEY (day, homeserver)
+);
+
+CREATE INDEX IF NOT EXISTS homeserver_daily_homeserver_idx ON homeserver_daily (homeserver, day);
+CREATE INDEX IF NOT EXISTS homeserver_daily_context_idx ON homeserver_daily (day, server_context);
+CREATE INDEX IF NOT EXISTS reports_local_timestamp_idx ON reports (local_timestamp);
+
+-- Backfill from the existing reports, using the same selection as the
+-- aggregation did so far: the latest report per homeserver and day
+INSERT INTO homeserver_daily
+SELECT
+    DISTINCT ON (homeserver, reports.local_timestamp::DATE)
+    local_timestamp::DATE,
+    homeserver,
+    id,
+    local_timestamp,
+    uptime_seconds,
+    total_users,
+    total_nonbridged_users,
+    total_room_count,
+    daily_active_users,
+    daily_active_rooms,
+    daily_messages,
+    daily_sent_messages,
+    daily_active_e2ee_rooms,
+    daily_e2ee_messages,
+    daily_sent_e2ee_messages,
+    monthly_active_users,
+    r30_users_all,
+    r30_users_android,
+    r30_users_ios,
+    r30_users_electron,
+    r30_users_web,
+    r30v2_users_all,
+    r30v2_users_android,
+    r30v2_users_ios,
+    r30v2_users_electron,
+    r30v2_users_web,
+    cpu_average,
+    memory_rss,
+    cache_factor,
+    event_cache_size,
+    user_agent,
+    daily_user_type_native,
+    daily_user_type_bridged,
+    daily_user_type_guest,
+    python_version,
+    database_engine,
+    database_server_version,
+    server_context,
+    log_level
+FROM reports
+WHERE homeserver IS NOT NULL AND local_timestamp IS NOT NULL
+ORDER BY homeserver, reports.local_timestamp::DATE, reports.local_timestamp DESC;
@@ -0,0 +1,14 @@
+-- Record how the daily value per homeserver was selected. Everything computed
+-- so far used the latest report of the day.
+ALTER TABLE homeserver_daily
+  ADD daily_strategy JSONB;
+
+ALTER TABLE aggregated_stats
+  ADD daily_strategy JSONB;
+
+ALTER TABLE aggregated_stats_by_context
+  ADD daily_strategy JSONB;
+
+UPDATE homeserver_daily SET daily_strategy = '{"default": "latest"}';
+UPDATE aggregated_stats SET daily_strategy = '{"default": "latest"}';
+UPDATE aggregated_stats_by_context SET daily_strategy = '{"default": "latest"}';
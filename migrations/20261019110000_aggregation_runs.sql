@@ -0,0 +1,26 @@
+-- Log of aggregation runs, and provenance of every aggregated row
+CREATE TABLE IF NOT EXISTS aggregation_runs
+(
+    id BIGSERIAL PRIMARY KEY,
+    day date NOT NULL,
+    scope TEXT NOT NULL,
+    started_at timestamp with time zone NOT NULL DEFAULT now(),
+    finished_at timestamp with time zone,
+    status TEXT NOT NULL DEFAULT 'running',
+    rows_written BIGINT,
+    report_count BIGINT,
+    error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS aggregation_runs_day_idx ON aggregation_runs (day, scope);
+
+ALTER TABLE homeserver_daily
+  ADD report_count BIGINT;
+
+ALTER TABLE aggregated_stats
+  ADD computed_at timestamp with time zone,
+  ADD report_count BIGINT;
+
+ALTER TABLE aggregated_stats_by_context
+  ADD computed_at timestamp with time zone,
+  ADD report_count BIGINT;
@@ -0,0 +1,6 @@
+-- Number of homeservers which contributed a value, per metric
+ALTER TABLE aggregated_stats
+  ADD coverage JSONB;
+
+ALTER TABLE aggregated_stats_by_context
+  ADD coverage JSONB;
@@ -0,0 +1,2 @@
+-- Speeds up looking up the latest report of every homeserver
+CREATE INDEX IF NOT EXISTS reports_homeserver_local_timestamp_idx ON reports (homeserver, local_timestamp);
@@ -0,0 +1,99 @@
+-- Daily counts of new, returning and churned homeservers. A homeserver churns
+-- on the day it has gone `churn_days` days without reports, and is returning
+-- if it reports again after having churned.
+CREATE TABLE IF NOT EXISTS homeserver_churn
+(
+    day date PRIMARY KEY,
+    active_homeservers BIGINT NOT NULL,
+    new_homeservers BIGINT NOT NULL,
+    returning_homeservers BIGINT NOT NULL,
+    churned_homeservers BIGINT NOT NULL,
+    churn_days INTEGER NOT NULL,
+    computed_at timestamp with time zone NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS homeserver_churn_by_context
+(
+    day date NOT NULL,
+    server_context TEXT NOT NULL,
+    active_homeservers BIGINT NOT NULL,
+    new_homeservers BIGINT NOT NULL,
+    returning_homeservers BIGINT NOT NULL,
+    churned_homeservers BIGINT NOT NULL,
+    churn_days INTEGER NOT NULL,
+    computed_at timestamp with time zone NOT NULL DEFAULT now(),
+    PRIMARY KEY (day, server_context)
+);
+
+-- Backfill with the default of 30 churn days
+WITH
+  events AS (
+    SELECT
+      day,
+      LAG(day) OVER w AS previous_day,
+      LEAD(day) OVER w AS next_day
+    FROM
+      homeserver_daily
+    WINDOW
+      w AS (PARTITION BY homeserver ORDER BY day)
+  )
+INSERT INTO
+  homeserver_churn (
+    day, active_homeservers, new_homeservers, returning_homeservers, churned_homeservers, churn_days
+  )
+SELECT
+  day,
+  COUNT(*) FILTER (WHERE active),
+  COUNT(*) FILTER (WHERE active AND previous_day IS NULL),
+  COUNT(*) FILTER (WHERE active AND day - previous_day > 30),
+  COUNT(*) FILTER (WHERE NOT active),
+  30
+FROM
+  (
+    SELECT day, previous_day, TRUE AS active FROM events
+    UNION ALL
+    SELECT day + 30, NULL, FALSE FROM events
+    WHERE (next_day IS NULL OR next_day - day > 30) AND day + 30 <= CURRENT_DATE
+  ) changes
+GROUP BY
+  day
+ON CONFLICT DO NOTHING;
+
+WITH
+  events AS (
+    SELECT
+      day,
+      server_context,
+      LAG(day) OVER w AS previous_day,
+      LEAD(day) OVER w AS next_day
+    FROM
+      homeserver_daily
+    WHERE
+      server_context IS NOT NULL
+    WINDOW
+      w AS (PARTITION BY homeserver, server_context ORDER BY day)
+  )
+INSERT INTO
+  homeserver_churn_by_context (
+    day, server_context, active_homeservers, new_homeservers, returning_homeservers,
+    churned_homeservers, churn_days
+  )
+SELECT
+  day,
+  server_context,
+  COUNT(*) FILTER (WHERE active),
+  COUNT(*) FILTER (WHERE active AND previous_day IS NULL),
+  COUNT(*) FILTER (WHERE active AND day - previous_day > 30),
+  COUNT(*) FILTER (WHERE NOT active),
+  30
+FROM
+  (
+    SELECT day, server_context, previous_day, TRUE AS active FROM events
+    UNION ALL
+    SELECT day + 30, server_context, NULL, FALSE FROM events
+    WHERE (next_day IS NULL OR next_day - day > 30) AND day + 30 <= CURRENT_DATE
+  ) changes
+GROUP BY
+  day,
+  server_context
+ON CONFLICT DO NOTHING;
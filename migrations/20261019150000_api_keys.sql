@@ -0,0 +1,10 @@
+-- API keys for the read endpoints. Only the SHA-256 hash of a key is stored,
+-- the scopes are `stats:global`, `stats:context:<name>` and `admin`.
+CREATE TABLE IF NOT EXISTS api_keys
+(
+    id BIGSERIAL PRIMARY KEY,
+    label TEXT NOT NULL,
+    key_hash BYTEA NOT NULL UNIQUE,
+    scopes TEXT[] NOT NULL,
+    created_at timestamp with time zone NOT NULL DEFAULT now()
+);
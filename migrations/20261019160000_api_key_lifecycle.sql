@@ -0,0 +1,6 @@
+-- Expiry, revocation and last use of API keys. Revoked keys are kept to know
+-- what they were used for.
+ALTER TABLE api_keys
+  ADD expires_at timestamp with time zone,
+  ADD revoked_at timestamp with time zone,
+  ADD last_used_at timestamp with time zone;
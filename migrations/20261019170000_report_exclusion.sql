@@ -0,0 +1,3 @@
+-- Reports matched by an `exclude` ingest rule are stored, but not aggregated
+ALTER TABLE reports
+  ADD excluded BOOLEAN NOT NULL DEFAULT false;
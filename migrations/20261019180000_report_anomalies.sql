@@ -0,0 +1,11 @@
+-- Score of each report against the history of its homeserver. Flagged reports
+-- are left out of the aggregation until an admin confirms them.
+ALTER TABLE reports
+  ADD anomaly_score DOUBLE PRECISION,
+  ADD anomalies JSONB,
+  ADD review_status TEXT CHECK (review_status IN ('flagged', 'confirmed', 'rejected')),
+  ADD reviewed_at TIMESTAMPTZ;
+
+CREATE INDEX reports_flagged_idx ON reports (id)
+WHERE
+  review_status = 'flagged';
@@ -0,0 +1,14 @@
+-- Administrative actions, who took them and how they turned out
+CREATE TABLE audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- Label of the API key, `admin_token`, `cli:<user>` or `system`
+  actor TEXT NOT NULL,
+  api_key_id BIGINT REFERENCES api_keys (id),
+  action TEXT NOT NULL,
+  parameters JSONB NOT NULL DEFAULT '{}',
+  outcome TEXT NOT NULL CHECK (outcome IN ('succeeded', 'failed')),
+  error TEXT
+);
+
+CREATE INDEX audit_log_created_at_idx ON audit_log (created_at);
@@ -0,0 +1,7 @@
+-- Homeservers whose data was erased on request, and whether their reports are
+-- refused from then on
+CREATE TABLE homeserver_tombstones (
+  homeserver TEXT PRIMARY KEY,
+  erased_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  refuse_reports BOOLEAN NOT NULL
+);
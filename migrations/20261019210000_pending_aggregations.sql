@@ -0,0 +1,5 @@
+-- Days to aggregate again after an erasure, until they have been
+CREATE TABLE pending_aggregations (
+  day DATE PRIMARY KEY,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::bail;
+use axum::extract::{FromRequestParts, Path, Query};
+use axum_extra::TypedHeader;
+use axum_extra::headers::Authorization;
+use axum_extra::headers::authorization::Bearer;
+use http::StatusCode;
+use http::request::Parts;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::model::NewApiKey;
+use crate::settings::{DBSettings, ServerSettings};
+
+/// Permission granted to an API key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    /// `stats:global`, the global aggregated stats
+    GlobalStats,
+    /// `stats:context:<name>`, the aggregated stats of a single server context
+    Context(String),
+    /// `admin`, every endpoint including the admin ones
+    Admin,
+}
+
+impl Scope {
+    /// Whether this scope gives access to endpoints requiring `required`
+    pub fn grants(&self, required: &Self) -> bool {
+        *self == Self::Admin || self == required
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stats:global" => Ok(Self::GlobalStats),
+            "admin" => Ok(Self::Admin),
+            _ => match s.strip_prefix("stats:context:") {
+                Some(context) if !context.is_empty() => Ok(Self::Context(context.to_owned())),
+                _ => bail!("unknown scope {s:?}"),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GlobalStats => write!(f, "stats:global"),
+            Self::Context(context) => write!(f, "stats:context:{context}"),
+            Self::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Prefix of generated API keys, making them easy to spot
+const KEY_PREFIX: &str = "bdr_";
+
+/// Hash of an API key as stored in the database
+pub fn hash_key(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+/// A new random API key, along with its hash
+pub fn generate_key() -> (String, Vec<u8>) {
+    let mut bytes = [0_u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let key = bytes.iter().fold(KEY_PREFIX.to_owned(), |key, byte| {
+        format!("{key}{byte:02x}")
+    });
+    let hash = hash_key(&key);
+    (key, hash)
+}
+
+/// Checks the label and scopes of a new API key
+pub fn validate_new_key(key: &NewApiKey) -> anyhow::Result<()> {
+    if key.label.trim().is_empty() {
+        bail!("the label must not be empty");
+    }
+    if key.scopes.is_empty() {
+        bail!("at least one scope is required");
+    }
+    for scope in &key.scopes {
+        scope.parse::<Scope>()?;
+    }
+    Ok(())
+}
+
+/// Who took an administrative action, as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor {
+    /// Label of the API key, `admin_token`, `cli:<user>` or `system`
+    pub label: String,
+    pub api_key_id: Option<i64>,
+}
+
+impl Actor {
+    fn admin_token() -> Self {
+        Self {
+            label: "admin_token".to_owned(),
+            api_key_id: None,
+        }
+    }
+
+    /// The server itself, e.g. reloading the ingest rules
+    pub fn system() -> Self {
+        Self {
+            label: "system".to_owned(),
+            api_key_id: None,
+        }
+    }
+
+    /// The user running a command line subcommand
+    pub fn cli() -> Self {
+        let user = std::env::var("USER")
+            .or_else(|_| std::env::var("LOGNAME"))
+            .unwrap_or_else(|_| "unknown".to_owned());
+        Self {
+            label: format!("cli:{user}"),
+            api_key_id: None,
+        }
+    }
+}
+
+/// Extractor guarding the admin endpoints, which require the configured
+/// `server.admin_token` or an API key with the `admin` scope as bearer token
+#[derive(Debug, Clone)]
+pub struct Admin(pub Actor);
+
+impl FromRequestParts<Arc<DBSettings>> for Admin {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<DBSettings>,
+    ) -> Result<Self, Self::Rejection> {
+        authorize(parts, state, &Scope::Admin).await.map(Self)
+    }
+}
+
+/// Extractor enforcing the API key scopes of the aggregated stats endpoints,
+/// applied to them as middleware. Routes with a `context` path parameter
+/// require the scope of that server context, all others `stats:global`.
+/// Aggregating on demand with `?generate=true` requires `admin`, and the
+/// admin listener if there is one.
+///
+/// Everyone may read the stats if `server.public_stats` is enabled. The
+/// [`Actor`] of authorized requests is added to their extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct StatsScope;
+
+/// Marks requests to the listener on `server.host` while `server.admin_host`
+/// is set, which refuses on-demand aggregation
+#[derive(Debug, Clone, Copy)]
+pub struct PublicListener;
+
+/// The query parameter triggering on-demand aggregation
+#[derive(Debug, Deserialize)]
+struct GenerateParam {
+    generate: Option<bool>,
+}
+
+impl FromRequestParts<Arc<DBSettings>> for StatsScope {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<DBSettings>,
+    ) -> Result<Self, Self::Rejection> {
+        // Invalid values are rejected by the handlers
+        let generate = Query::<GenerateParam>::try_from_uri(&parts.uri)
+            .is_ok_and(|Query(param)| param.generate == Some(true));
+        if generate {
+            if parts.extensions.get::<PublicListener>().is_some() {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    "on-demand aggregation is only available on the admin listener".to_owned(),
+                ));
+            }
+            let actor = authorize(parts, state, &Scope::Admin).await?;
+            parts.extensions.insert(actor);
+            return Ok(Self);
+        }
+        if parts
+            .extensions
+            .get::<Arc<ServerSettings>>()
+            .is_some_and(|settings| settings.public_stats)
+        {
+            return Ok(Self);
+        }
+
+        let params = Option::<Path<HashMap<String, String>>>::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| (rejection.status(), rejection.body_text()))?;
+        let required = match params.and_then(|Path(mut params)| params.remove("context")) {
+            Some(context) => Scope::Context(context),
+            None => Scope::GlobalStats,
+        };
+
+        let actor = authorize(parts, state, &required).await?;
+        parts.extensions.insert(actor);
+        Ok(Self)
+    }
+}
+
+/// Checks that the bearer token is the admin token, or an API key with a
+/// scope granting `required`, and tells who it belongs to
+async fn authorize(
+    parts: &mut Parts,
+    db_settings: &Arc<DBSettings>,
+    required: &Scope,
+) -> Result<Actor, (StatusCode, String)> {
+    let admin_token = parts
+        .extensions
+        .get::<Arc<ServerSettings>>()
+        .and_then(|settings| settings.admin_token.clone());
+
+    let TypedHeader(Authorization(bearer)) =
+        TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, db_settings)
+            .await
+            .map_err(|_| (StatusCode::UNAUTHORIZED, "missing bearer token".to_owned()))?;
+
+    if admin_token
+        .as_ref()
+        .is_some_and(|expected| constant_time_eq(bearer.token().as_bytes(), expected.as_bytes()))
+    {
+        return Ok(Actor::admin_token());
+    }
+
+    let key = crate::database::use_api_key(db_settings, &hash_key(bearer.token()))
+        .await
+        .map_err(|err| {
+            log::error!("{err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal server error".to_owned(),
+            )
+        })?;
+    let Some(key) = key else {
+        return Err((StatusCode::UNAUTHORIZED, "invalid bearer token".to_owned()));
+    };
+
+    let granted = key.scopes.iter().any(|scope| match scope.parse::<Scope>() {
+        Ok(scope) => scope.grants(required),
+        Err(err) => {
+            log::warn!("API key {} has an invalid scope: {err}", key.id);
+            false
+        }
+    });
+    if granted {
+        Ok(Actor {
+            label: key.label,
+            api_key_id: Some(key.id),
+        })
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            format!("the API key lacks the `{required}` scope"),
+        ))
+    }
+}
+
+/// Compares secrets without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,131 @@
+use std::io::Write;
+
+use anyhow::{Context, Result, bail};
+use clap::ArgMatches;
+use serde::Serialize;
+use serde_json::json;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use crate::auth::Actor;
+use crate::model::{ApiKeySecret, NewApiKey};
+use crate::settings::DBSettings;
+use crate::{auth, database};
+
+/// Runs a `keys` subcommand, printing the affected keys as JSON. Changes are
+/// recorded in the audit log as taken by `cli:<user>`.
+pub async fn run_keys(db_settings: &DBSettings, opts: &ArgMatches) -> Result<()> {
+    // Fail with a proper error instead of exiting on the first query
+    database::connect_pg_gracefully(&db_settings.url).await?;
+
+    match opts.subcommand() {
+        Some(("list", _)) => {
+            let (keys, _) = database::get_api_keys(db_settings, i64::MAX, 0).await?;
+            print(&keys)
+        }
+        Some(("create", opts)) => {
+            let key = NewApiKey {
+                label: opts
+                    .get_one::<String>("label")
+                    .expect("required argument")
+                    .clone(),
+                scopes: opts
+                    .get_many::<String>("scope")
+                    .expect("required argument")
+                    .cloned()
+                    .collect(),
+                expires_at: opts
+                    .get_one::<String>("expires-at")
+                    .map(|at| parse_time(at))
+                    .transpose()?,
+            };
+            audited(db_settings, "api_key.create", json!(key), async {
+                auth::validate_new_key(&key)?;
+                let (secret, hash) = auth::generate_key();
+                let key = database::create_api_key(db_settings, &key, &hash).await?;
+                print(&ApiKeySecret { key, secret })
+            })
+            .await
+        }
+        Some(("rotate", opts)) => {
+            let id = id(opts);
+            audited(db_settings, "api_key.rotate", json!({ "id": id }), async {
+                let (secret, hash) = auth::generate_key();
+                let Some(key) = database::rotate_api_key(db_settings, id, &hash).await? else {
+                    bail!("no API key with ID {id} which hasn't been revoked");
+                };
+                print(&ApiKeySecret { key, secret })
+            })
+            .await
+        }
+        Some(("expire", opts)) => {
+            let id = id(opts);
+            let expires_at = if opts.get_flag("never") {
+                None
+            } else {
+                Some(
+                    opts.get_one::<String>("at")
+                        .map_or_else(|| Ok(OffsetDateTime::now_utc()), |at| parse_time(at))?,
+                )
+            };
+            let parameters = json!({
+                "id": id,
+                "expires_at": expires_at.map(|at| at.format(&Rfc3339)).transpose()?,
+            });
+            audited(db_settings, "api_key.expire", parameters, async {
+                let Some(key) = database::expire_api_key(db_settings, id, expires_at).await? else {
+                    bail!("no API key with ID {id}");
+                };
+                print(&key)
+            })
+            .await
+        }
+        Some(("revoke", opts)) => {
+            let id = id(opts);
+            audited(db_settings, "api_key.revoke", json!({ "id": id }), async {
+                let Some(key) = database::revoke_api_key(db_settings, id).await? else {
+                    bail!("no API key with ID {id}");
+                };
+                print(&key)
+            })
+            .await
+        }
+        _ => unreachable!("subcommand required"),
+    }
+}
+
+/// Takes an administrative action and records it in the audit log, failing if
+/// either fails
+async fn audited(
+    db_settings: &DBSettings,
+    action: &str,
+    parameters: serde_json::Value,
+    run: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    let result = run.await;
+    let error = result.as_ref().err().map(|err| format!("{err:#}"));
+    let recorded = database::record_audit(
+        db_settings,
+        &Actor::cli(),
+        action,
+        &parameters,
+        error.as_deref(),
+    )
+    .await;
+    result.and(recorded)
+}
+
+fn id(opts: &ArgMatches) -> i64 {
+    *opts.get_one::<i64>("id").expect("required argument")
+}
+
+fn parse_time(time: &str) -> Result<OffsetDateTime> {
+    OffsetDateTime::parse(time, &Rfc3339).with_context(|| format!("invalid RFC 3339 time {time:?}"))
+}
+
+fn print(value: &impl Serialize) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value)?;
+    writeln!(stdout)?;
+    Ok(())
+}
@@ -1,31 +1,73 @@
+use std::collections::{BTreeMap, HashMap};
 use std::process;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use log::info;
-use sqlx::PgPool;
-use tokio::{sync::mpsc::Receiver, time::interval};
+use sqlx::{FromRow, PgConnection, PgPool, Postgres, QueryBuilder, Row};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{Instant, interval};
 use tracing::instrument;
 
-use crate::model::{AggregatedStats, AggregatedStatsByContext, Report};
-use crate::settings::DBSettings;
+use crate::auth::Actor;
+use crate::leader::LeaderLock;
+use crate::metrics;
+use crate::model::{
+    AGGREGATED_METRICS, AggregateFunction, AggregatedStats, AggregatedStatsByContext, ApiKey,
+    AuditEntry, AuditOutcome, ContextSummary, DAILY_METRICS, DailyStrategy, Dimension, Erasure,
+    ErasureRequest, FlaggedReport, HomeserverChurn, HomeserverChurnByContext, HomeserverDay,
+    HomeserverSort, HomeserverStatus, HomeserverSummary, NewApiKey, QueryGroup, RankedHomeserver,
+    Report, ReviewStatus, SelectionStrategy, SortOrder,
+};
+use crate::settings::{AggregationSettings, AnomalySettings, DBSettings};
+
+/// Runs the scheduled jobs, but only while this replica holds the leader lock.
+/// Standby replicas keep trying to acquire the lock every `leader_poll_seconds`.
+pub async fn aggregate_loop(settings: &DBSettings, aggregation: &AggregationSettings) {
+    let mut lock = if aggregation.leader_election {
+        LeaderLock::new(&settings.url, aggregation.leader_lock_key)
+    } else {
+        LeaderLock::disabled()
+    };
+    let period = Duration::from_secs(aggregation.interval_seconds);
+    let poll = &mut interval(Duration::from_secs(aggregation.leader_poll_seconds).min(period));
+    let mut last_run: Option<Instant> = None;
 
-pub async fn aggregate_loop(settings: &DBSettings) {
-    let interval = &mut interval(std::time::Duration::new(3600, 0));
     loop {
-        let today = time::OffsetDateTime::now_utc().date();
-        if let Err(err) = aggregate_stats(settings, today).await {
-            log::error!("{err:?}");
-            process::exit(-1);
+        if lock.ensure().await && last_run.is_none_or(|last_run| last_run.elapsed() >= period) {
+            last_run = Some(Instant::now());
+            run_scheduled_jobs(settings, aggregation).await;
         }
-        if let Err(err) = aggregate_stats_by_context(settings, today).await {
-            log::error!("{err:?}");
-            process::exit(-1);
-        }
-        interval.tick().await;
+        poll.tick().await;
+    }
+}
+
+async fn run_scheduled_jobs(settings: &DBSettings, aggregation: &AggregationSettings) {
+    let today = time::OffsetDateTime::now_utc().date();
+    if let Err(err) = aggregate_day(settings, &aggregation.daily_strategy, today).await {
+        log::error!("{err:?}");
+        process::exit(-1);
+    }
+    if let Err(err) = aggregate_churn(settings, aggregation.churn_days, today).await {
+        log::error!("{err:?}");
+        process::exit(-1);
+    }
+    let pending = match pending_aggregations(settings).await {
+        Ok(days) => aggregate_pending(settings, aggregation, &days).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = pending {
+        log::error!("{err:?}");
+        process::exit(-1);
     }
 }
 
-pub async fn insert_reports_loop(settings: &DBSettings, mut rx: Receiver<Report>) {
+pub async fn insert_reports_loop(
+    settings: &DBSettings,
+    anomaly: &AnomalySettings,
+    mut rx: Receiver<Report>,
+) {
     let pool = get_db_pool(settings).await;
 
     loop {
@@ -41,387 +83,2353 @@ pub async fn insert_reports_loop(settings: &DBSettings, mut rx: Receiver<Report>
             }
         };
 
-        if let Err(err) = save_report(&pool, &report)
-            .await
-            .context("failed writing report to database.")
-        {
-            log::error!("{err:?}");
-            process::exit(-1);
+        let timer = metrics::INSERT_LATENCY.start_timer();
+        let result = store_report(&pool, &report, anomaly)
+            .await
+            .context("failed writing report to database.");
+        timer.observe_duration();
+        if let Err(err) = result {
+            log::error!("{err:?}");
+            process::exit(-1);
+        }
+    }
+}
+
+pub async fn connect_pg_gracefully(url: &str) -> Result<PgPool> {
+    PgPool::connect(url)
+        .await
+        .context("failed connecting to PostgreSQL server.")
+}
+
+async fn connect_pg(url: &str) -> PgPool {
+    let pool = match connect_pg_gracefully(url).await {
+        Ok(pool) => pool,
+        Err(err) => {
+            log::error!("{err:?}");
+            process::exit(-1);
+        }
+    };
+
+    if let Err(err) = sqlx::migrate!()
+        .run(&pool)
+        .await
+        .context("failed to run migrations")
+    {
+        log::error!("{err:?}");
+        process::exit(-1);
+    }
+
+    pool
+}
+
+pub async fn get_db_pool(DBSettings { url }: &DBSettings) -> PgPool {
+    use tokio::sync::OnceCell;
+    static PG_POOL_CELL: OnceCell<PgPool> = OnceCell::const_new();
+
+    PG_POOL_CELL
+        .get_or_init(|| async { connect_pg(url).await })
+        .await
+        .clone()
+}
+
+/// Report fields which are always taken from the latest report of the day
+const DAILY_LATEST_FIELDS: &[&str] = &[
+    "user_agent",
+    "python_version",
+    "database_engine",
+    "database_server_version",
+    "server_context",
+    "log_level",
+];
+
+/// SQL expression selecting the daily value of `metric` from the reports of one
+/// homeserver. `$1` is the day and `$2` the cutoff time.
+fn daily_value_sql(metric: &str, strategy: SelectionStrategy) -> String {
+    let latest = format!("(ARRAY_AGG({metric} ORDER BY local_timestamp DESC, id DESC))[1]");
+    match strategy {
+        SelectionStrategy::Latest => latest,
+        SelectionStrategy::Max => format!("MAX({metric})"),
+        SelectionStrategy::Mean if metric == "cache_factor" => format!("AVG({metric})"),
+        SelectionStrategy::Mean => format!("ROUND(AVG({metric}))::BIGINT"),
+        SelectionStrategy::FirstAfterCutoff => {
+            let after_cutoff = "local_timestamp >= $1::DATE + $2::TIME";
+            format!(
+                "CASE WHEN COUNT(*) FILTER (WHERE {after_cutoff}) > 0 \
+                 THEN (ARRAY_AGG({metric} ORDER BY local_timestamp, id) FILTER (WHERE {after_cutoff}))[1] \
+                 ELSE {latest} END"
+            )
+        }
+    }
+}
+
+/// Selects the representative values of every homeserver for the given day
+/// into `homeserver_daily`, according to the configured strategy. All higher
+/// aggregates are derived from that table instead of scanning `reports` again.
+/// Reports excluded by an ingest rule or flagged as anomalous are left out.
+#[instrument(skip(conn))]
+async fn refresh_homeserver_daily(
+    conn: &mut PgConnection,
+    strategy: &DailyStrategy,
+    day: sqlx::types::time::Date,
+) -> Result<u64> {
+    let columns = DAILY_METRICS
+        .iter()
+        .chain(DAILY_LATEST_FIELDS)
+        .copied()
+        .collect::<Vec<_>>();
+    let values = DAILY_METRICS
+        .iter()
+        .map(|metric| daily_value_sql(metric, strategy.for_metric(metric)))
+        .chain(
+            DAILY_LATEST_FIELDS
+                .iter()
+                .map(|field| daily_value_sql(field, SelectionStrategy::Latest)),
+        )
+        .collect::<Vec<_>>();
+    let query = format!(
+        r#"
+        INSERT INTO
+          homeserver_daily (
+            day, homeserver, report_id, local_timestamp, report_count, daily_strategy, {columns}
+          )
+        SELECT
+          $1::DATE,
+          homeserver,
+          (ARRAY_AGG(id ORDER BY local_timestamp DESC, id DESC))[1],
+          MAX(local_timestamp),
+          COUNT(*),
+          $3,
+          {values}
+        FROM
+          reports
+        WHERE
+          local_timestamp >= $1::DATE
+          AND local_timestamp < $1::DATE + 1
+          AND homeserver IS NOT NULL
+          AND NOT excluded
+          AND (
+            review_status IS NULL
+            OR review_status = 'confirmed'
+          )
+        GROUP BY
+          homeserver;"#,
+        columns = columns.join(", "),
+        values = values.join(",\n          "),
+    );
+
+    sqlx::query!("DELETE FROM homeserver_daily WHERE day = $1", day)
+        .execute(&mut *conn)
+        .await
+        .context("could not clear homeserver_daily")?;
+
+    let rows = sqlx::query(&query)
+        .bind(day)
+        .bind(strategy.cutoff.as_deref().unwrap_or("00:00"))
+        .bind(sqlx::types::Json(strategy))
+        .execute(&mut *conn)
+        .await
+        .context("could not select daily values per homeserver")?
+        .rows_affected();
+
+    Ok(rows)
+}
+
+/// Number of aggregated rows and contributing reports of an aggregation run
+#[derive(Debug, Clone, Copy)]
+struct RunCounts {
+    rows_written: u64,
+    report_count: i64,
+}
+
+/// Records an aggregation run of `scope` for `day` in `aggregation_runs`,
+/// along with its outcome.
+async fn record_run(
+    pool: &PgPool,
+    scope: &str,
+    day: sqlx::types::time::Date,
+    run: impl Future<Output = Result<RunCounts>>,
+) -> Result<RunCounts> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO aggregation_runs (day, scope) VALUES ($1, $2) RETURNING id",
+        day,
+        scope
+    )
+    .fetch_one(pool)
+    .await
+    .context("could not record aggregation run")?;
+
+    let started = Instant::now();
+    let result = run.await;
+    let (status, counts, error) = match &result {
+        Ok(counts) => ("succeeded", Some(*counts), None),
+        Err(err) => ("failed", None, Some(format!("{err:?}"))),
+    };
+    metrics::AGGREGATION_DURATION
+        .with_label_values(&[scope, status])
+        .observe(started.elapsed().as_secs_f64());
+    if result.is_ok() {
+        #[allow(clippy::cast_precision_loss)]
+        metrics::AGGREGATION_LAST_SUCCESS
+            .with_label_values(&[scope])
+            .set(time::OffsetDateTime::now_utc().unix_timestamp() as f64);
+    }
+    sqlx::query!(
+        r#"
+        UPDATE
+          aggregation_runs
+        SET
+          finished_at = now(),
+          status = $2,
+          rows_written = $3,
+          report_count = $4,
+          error = $5
+        WHERE
+          id = $1"#,
+        id,
+        status,
+        counts.and_then(|counts| i64::try_from(counts.rows_written).ok()),
+        counts.map(|counts| counts.report_count),
+        error,
+    )
+    .execute(pool)
+    .await
+    .context("could not record outcome of aggregation run")?;
+
+    result
+}
+
+/// First key of the advisory locks taken on aggregated days, the second one
+/// being the day
+const DAY_LOCK_NAMESPACE: i32 = 0x6167_6772;
+
+/// Waits until no other aggregation of `day` is running, on any replica. The
+/// lock is held until the end of the transaction.
+async fn lock_day(conn: &mut PgConnection, day: sqlx::types::time::Date) -> Result<()> {
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock($1, $2)",
+        DAY_LOCK_NAMESPACE,
+        day.to_julian_day()
+    )
+    .execute(conn)
+    .await
+    .context("failed locking aggregated day")?;
+    Ok(())
+}
+
+/// Number of reports which went into the `homeserver_daily` rows of `day`
+async fn daily_report_count(conn: &mut PgConnection, day: sqlx::types::time::Date) -> Result<i64> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        SELECT
+          COALESCE(SUM(report_count), 0)::BIGINT AS "count!"
+        FROM
+          homeserver_daily
+        WHERE
+          day = $1"#,
+        day
+    )
+    .fetch_one(conn)
+    .await?)
+}
+
+/// Refreshes the `homeserver_daily` snapshot of `day`, which
+/// [`aggregate_stats`] and [`aggregate_stats_by_context`] are derived from
+#[instrument(skip(db_settings, strategy))]
+pub async fn refresh_snapshot(
+    db_settings: &DBSettings,
+    strategy: &DailyStrategy,
+    day: sqlx::types::time::Date,
+) -> Result<()> {
+    let pool = get_db_pool(db_settings).await;
+    let counts = record_run(&pool, "snapshot", day, async {
+        let mut tx = pool.begin().await?;
+        lock_day(&mut tx, day).await?;
+        let rows_written = refresh_homeserver_daily(&mut tx, strategy, day).await?;
+        let report_count = daily_report_count(&mut tx, day).await?;
+        tx.commit().await?;
+
+        Ok(RunCounts {
+            rows_written,
+            report_count,
+        })
+    })
+    .await?;
+
+    info!(
+        "Snapshot of {day} with {} homeservers refreshed successfully from {} reports",
+        counts.rows_written, counts.report_count
+    );
+
+    Ok(())
+}
+
+/// Refreshes the snapshot of `day` once, then derives both aggregates from it
+pub async fn aggregate_day(
+    db_settings: &DBSettings,
+    strategy: &DailyStrategy,
+    day: sqlx::types::time::Date,
+) -> Result<()> {
+    refresh_snapshot(db_settings, strategy, day).await?;
+    aggregate_stats(db_settings, day).await?;
+    aggregate_stats_by_context(db_settings, day).await
+}
+
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(db_settings))]
+pub async fn aggregate_stats(db_settings: &DBSettings, day: sqlx::types::time::Date) -> Result<()> {
+    let pool = get_db_pool(db_settings).await;
+    let counts = record_run(&pool, "global", day, async {
+        let mut tx = pool.begin().await?;
+        lock_day(&mut tx, day).await?;
+
+        // A day without reports left, e.g. after an erasure, has no stats
+        sqlx::query!(
+            r#"
+            DELETE FROM aggregated_stats
+            WHERE
+              day = $1
+              AND NOT EXISTS (
+                SELECT
+                FROM
+                  homeserver_daily
+                WHERE
+                  day = $1
+              )"#,
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not clear stale aggregated stats")?;
+
+        let rows_written = sqlx::query!(
+            r#"
+            INSERT INTO
+              aggregated_stats (
+                day,
+                total_users,
+                total_nonbridged_users,
+                total_room_count,
+                daily_active_users,
+                daily_active_rooms,
+                daily_messages,
+                daily_sent_messages,
+                daily_active_e2ee_rooms,
+                daily_e2ee_messages,
+                daily_sent_e2ee_messages,
+                monthly_active_users,
+                r30_users_all,
+                r30_users_android,
+                r30_users_ios,
+                r30_users_electron,
+                r30_users_web,
+                r30v2_users_all,
+                r30v2_users_android,
+                r30v2_users_ios,
+                r30v2_users_electron,
+                r30v2_users_web,
+                daily_user_type_native,
+                daily_user_type_bridged,
+                daily_user_type_guest,
+                daily_active_homeservers,
+                daily_strategy,
+                computed_at,
+                report_count,
+                coverage
+              )
+            SELECT
+              day,
+              SUM(total_users),
+              SUM(total_nonbridged_users),
+              SUM(total_room_count),
+              SUM(daily_active_users),
+              SUM(daily_active_rooms),
+              SUM(daily_messages),
+              SUM(daily_sent_messages),
+              SUM(daily_active_e2ee_rooms),
+              SUM(daily_e2ee_messages),
+              SUM(daily_sent_e2ee_messages),
+              SUM(monthly_active_users),
+              SUM(r30_users_all),
+              SUM(r30_users_android),
+              SUM(r30_users_ios),
+              SUM(r30_users_electron),
+              SUM(r30_users_web),
+              SUM(r30v2_users_all),
+              SUM(r30v2_users_android),
+              SUM(r30v2_users_ios),
+              SUM(r30v2_users_electron),
+              SUM(r30v2_users_web),
+              SUM(daily_user_type_native),
+              SUM(daily_user_type_bridged),
+              SUM(daily_user_type_guest),
+              COUNT(homeserver),
+              (ARRAY_AGG(daily_strategy))[1],
+              now(),
+              SUM(report_count),
+              jsonb_build_object(
+                'total_users', COUNT(total_users),
+                'total_nonbridged_users', COUNT(total_nonbridged_users),
+                'total_room_count', COUNT(total_room_count),
+                'daily_active_users', COUNT(daily_active_users),
+                'daily_active_rooms', COUNT(daily_active_rooms),
+                'daily_messages', COUNT(daily_messages),
+                'daily_sent_messages', COUNT(daily_sent_messages),
+                'daily_active_e2ee_rooms', COUNT(daily_active_e2ee_rooms),
+                'daily_e2ee_messages', COUNT(daily_e2ee_messages),
+                'daily_sent_e2ee_messages', COUNT(daily_sent_e2ee_messages),
+                'monthly_active_users', COUNT(monthly_active_users),
+                'r30_users_all', COUNT(r30_users_all),
+                'r30_users_android', COUNT(r30_users_android),
+                'r30_users_ios', COUNT(r30_users_ios),
+                'r30_users_electron', COUNT(r30_users_electron),
+                'r30_users_web', COUNT(r30_users_web),
+                'r30v2_users_all', COUNT(r30v2_users_all),
+                'r30v2_users_android', COUNT(r30v2_users_android),
+                'r30v2_users_ios', COUNT(r30v2_users_ios),
+                'r30v2_users_electron', COUNT(r30v2_users_electron),
+                'r30v2_users_web', COUNT(r30v2_users_web),
+                'daily_user_type_native', COUNT(daily_user_type_native),
+                'daily_user_type_bridged', COUNT(daily_user_type_bridged),
+                'daily_user_type_guest', COUNT(daily_user_type_guest)
+              )
+            FROM
+              homeserver_daily
+            WHERE
+              day = $1
+            GROUP BY
+              day ON CONFLICT (day) DO
+            UPDATE
+            SET
+              total_users = excluded.total_users,
+              total_nonbridged_users = excluded.total_nonbridged_users,
+              total_room_count = excluded.total_room_count,
+              daily_active_users = excluded.daily_active_users,
+              daily_active_rooms = excluded.daily_active_rooms,
+              daily_messages = excluded.daily_messages,
+              daily_sent_messages = excluded.daily_sent_messages,
+              daily_active_e2ee_rooms = excluded.daily_active_e2ee_rooms,
+              daily_e2ee_messages = excluded.daily_e2ee_messages,
+              daily_sent_e2ee_messages = excluded.daily_sent_e2ee_messages,
+              monthly_active_users = excluded.monthly_active_users,
+              r30_users_all = excluded.r30_users_all,
+              r30_users_android = excluded.r30_users_android,
+              r30_users_ios = excluded.r30_users_ios,
+              r30_users_electron = excluded.r30_users_electron,
+              r30_users_web = excluded.r30_users_web,
+              r30v2_users_all = excluded.r30v2_users_all,
+              r30v2_users_android = excluded.r30v2_users_android,
+              r30v2_users_ios = excluded.r30v2_users_ios,
+              r30v2_users_electron = excluded.r30v2_users_electron,
+              r30v2_users_web = excluded.r30v2_users_web,
+              daily_user_type_native = excluded.daily_user_type_native,
+              daily_user_type_bridged = excluded.daily_user_type_bridged,
+              daily_user_type_guest = excluded.daily_user_type_guest,
+              daily_active_homeservers = excluded.daily_active_homeservers,
+              daily_strategy = excluded.daily_strategy,
+              computed_at = excluded.computed_at,
+              report_count = excluded.report_count,
+              coverage = excluded.coverage;"#,
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not aggregate stats")?
+        .rows_affected();
+
+        sqlx::query!(
+            r#"
+              WITH totals AS (
+                  SELECT
+                      day,
+                      SUM(daily_messages) OVER (
+                          ORDER BY day
+                          ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+                      ) AS total_messages,
+                      SUM(daily_e2ee_messages) OVER (
+                          ORDER BY day
+                          ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+                      ) AS total_e2ee_messages
+                  FROM aggregated_stats
+              )
+              UPDATE aggregated_stats t
+              SET
+                  total_messages = totals.total_messages,
+                  total_e2ee_messages = totals.total_e2ee_messages
+              FROM totals
+              WHERE t.day = totals.day AND t.day = $1;
+"#,
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not add total_messages and total_e2ee_messages to aggregated_stats")?;
+
+        let report_count = daily_report_count(&mut tx, day).await?;
+        tx.commit().await?;
+
+        Ok(RunCounts {
+            rows_written,
+            report_count,
+        })
+    })
+    .await?;
+
+    info!(
+        "Aggregated stats for {day} generated successfully from {} reports",
+        counts.report_count
+    );
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(db_settings))]
+pub async fn aggregate_stats_by_context(
+    db_settings: &DBSettings,
+    day: sqlx::types::time::Date,
+) -> Result<()> {
+    let pool = get_db_pool(db_settings).await;
+    let counts = record_run(&pool, "context", day, async {
+        let mut tx = pool.begin().await?;
+        lock_day(&mut tx, day).await?;
+
+        // Same for server contexts without reports left
+        sqlx::query!(
+            r#"
+            DELETE FROM aggregated_stats_by_context a
+            WHERE
+              day = $1
+              AND NOT EXISTS (
+                SELECT
+                FROM
+                  homeserver_daily hd
+                WHERE
+                  hd.day = $1
+                  AND hd.server_context = a.server_context
+              )"#,
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not clear stale aggregated stats by context")?;
+
+        let rows_written = sqlx::query!(
+            r#"
+            INSERT INTO
+              aggregated_stats_by_context (
+                day,
+                server_context,
+                total_users,
+                total_nonbridged_users,
+                total_room_count,
+                daily_active_users,
+                daily_active_rooms,
+                daily_messages,
+                daily_sent_messages,
+                daily_active_e2ee_rooms,
+                daily_e2ee_messages,
+                daily_sent_e2ee_messages,
+                monthly_active_users,
+                r30_users_all,
+                r30_users_android,
+                r30_users_ios,
+                r30_users_electron,
+                r30_users_web,
+                r30v2_users_all,
+                r30v2_users_android,
+                r30v2_users_ios,
+                r30v2_users_electron,
+                r30v2_users_web,
+                daily_user_type_native,
+                daily_user_type_bridged,
+                daily_user_type_guest,
+                daily_active_homeservers,
+                daily_strategy,
+                computed_at,
+                report_count,
+                coverage
+              )
+            SELECT
+              day,
+              server_context,
+              SUM(total_users),
+              SUM(total_nonbridged_users),
+              SUM(total_room_count),
+              SUM(daily_active_users),
+              SUM(daily_active_rooms),
+              SUM(daily_messages),
+              SUM(daily_sent_messages),
+              SUM(daily_active_e2ee_rooms),
+              SUM(daily_e2ee_messages),
+              SUM(daily_sent_e2ee_messages),
+              SUM(monthly_active_users),
+              SUM(r30_users_all),
+              SUM(r30_users_android),
+              SUM(r30_users_ios),
+              SUM(r30_users_electron),
+              SUM(r30_users_web),
+              SUM(r30v2_users_all),
+              SUM(r30v2_users_android),
+              SUM(r30v2_users_ios),
+              SUM(r30v2_users_electron),
+              SUM(r30v2_users_web),
+              SUM(daily_user_type_native),
+              SUM(daily_user_type_bridged),
+              SUM(daily_user_type_guest),
+              COUNT(homeserver),
+              (ARRAY_AGG(daily_strategy))[1],
+              now(),
+              SUM(report_count),
+              jsonb_build_object(
+                'total_users', COUNT(total_users),
+                'total_nonbridged_users', COUNT(total_nonbridged_users),
+                'total_room_count', COUNT(total_room_count),
+                'daily_active_users', COUNT(daily_active_users),
+                'daily_active_rooms', COUNT(daily_active_rooms),
+                'daily_messages', COUNT(daily_messages),
+                'daily_sent_messages', COUNT(daily_sent_messages),
+                'daily_active_e2ee_rooms', COUNT(daily_active_e2ee_rooms),
+                'daily_e2ee_messages', COUNT(daily_e2ee_messages),
+                'daily_sent_e2ee_messages', COUNT(daily_sent_e2ee_messages),
+                'monthly_active_users', COUNT(monthly_active_users),
+                'r30_users_all', COUNT(r30_users_all),
+                'r30_users_android', COUNT(r30_users_android),
+                'r30_users_ios', COUNT(r30_users_ios),
+                'r30_users_electron', COUNT(r30_users_electron),
+                'r30_users_web', COUNT(r30_users_web),
+                'r30v2_users_all', COUNT(r30v2_users_all),
+                'r30v2_users_android', COUNT(r30v2_users_android),
+                'r30v2_users_ios', COUNT(r30v2_users_ios),
+                'r30v2_users_electron', COUNT(r30v2_users_electron),
+                'r30v2_users_web', COUNT(r30v2_users_web),
+                'daily_user_type_native', COUNT(daily_user_type_native),
+                'daily_user_type_bridged', COUNT(daily_user_type_bridged),
+                'daily_user_type_guest', COUNT(daily_user_type_guest)
+              )
+            FROM
+              homeserver_daily
+            WHERE
+              day = $1
+              AND server_context IS NOT NULL
+            GROUP BY
+              server_context,
+              day
+            ON CONFLICT (day, server_context) DO
+            UPDATE
+            SET
+              total_users = excluded.total_users,
+              total_nonbridged_users = excluded.total_nonbridged_users,
+              total_room_count = excluded.total_room_count,
+              daily_active_users = excluded.daily_active_users,
+              daily_active_rooms = excluded.daily_active_rooms,
+              daily_messages = excluded.daily_messages,
+              daily_sent_messages = excluded.daily_sent_messages,
+              daily_active_e2ee_rooms = excluded.daily_active_e2ee_rooms,
+              daily_e2ee_messages = excluded.daily_e2ee_messages,
+              daily_sent_e2ee_messages = excluded.daily_sent_e2ee_messages,
+              monthly_active_users = excluded.monthly_active_users,
+              r30_users_all = excluded.r30_users_all,
+              r30_users_android = excluded.r30_users_android,
+              r30_users_ios = excluded.r30_users_ios,
+              r30_users_electron = excluded.r30_users_electron,
+              r30_users_web = excluded.r30_users_web,
+              r30v2_users_all = excluded.r30v2_users_all,
+              r30v2_users_android = excluded.r30v2_users_android,
+              r30v2_users_ios = excluded.r30v2_users_ios,
+              r30v2_users_electron = excluded.r30v2_users_electron,
+              r30v2_users_web = excluded.r30v2_users_web,
+              daily_user_type_native = excluded.daily_user_type_native,
+              daily_user_type_bridged = excluded.daily_user_type_bridged,
+              daily_user_type_guest = excluded.daily_user_type_guest,
+              daily_active_homeservers = excluded.daily_active_homeservers,
+              daily_strategy = excluded.daily_strategy,
+              computed_at = excluded.computed_at,
+              report_count = excluded.report_count,
+              coverage = excluded.coverage;"#,
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not aggregate stats")?
+        .rows_affected();
+
+        sqlx::query!(
+            r#"
+              WITH totals AS (
+                  SELECT
+                      day,
+                      server_context,
+                      SUM(daily_messages) OVER (
+                          PARTITION BY server_context
+                          ORDER BY day
+                          ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+                      ) AS total_messages,
+                      SUM(daily_e2ee_messages) OVER (
+                          PARTITION BY server_context
+                          ORDER BY day
+                          ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+                      ) AS total_e2ee_messages
+                  FROM aggregated_stats_by_context
+              )
+              UPDATE aggregated_stats_by_context t
+              SET
+                  total_messages = totals.total_messages,
+                  total_e2ee_messages = totals.total_e2ee_messages
+              FROM totals
+              WHERE t.day = totals.day AND t.server_context = totals.server_context AND t.day = $1;
+"#,
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context(
+            "could not add total_messages and total_e2ee_messages to aggregated_stats_by_context",
+        )?;
+
+        let report_count = daily_report_count(&mut tx, day).await?;
+        tx.commit().await?;
+
+        Ok(RunCounts {
+            rows_written,
+            report_count,
+        })
+    })
+    .await?;
+
+    info!(
+        "Aggregated stats for {day} with {} contexts generated successfully from {} reports",
+        counts.rows_written, counts.report_count
+    );
+
+    Ok(())
+}
+
+/// Aggregated stats which can be generated on demand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AggregationScope {
+    /// [`aggregate_stats`]
+    Global,
+    /// [`aggregate_stats_by_context`]
+    Context,
+}
+
+impl AggregationScope {
+    /// Scope of the runs in `aggregation_runs`
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::Context => "context",
+        }
+    }
+}
+
+/// Outcome of an on-demand aggregation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generation {
+    /// The day was aggregated for this request
+    Generated,
+    /// The day was being aggregated already, and the request waited for it
+    Joined,
+    /// The day was aggregated recently and wasn't aggregated again, it may be
+    /// aggregated again after the given number of seconds
+    Throttled(u64),
+}
+
+impl Generation {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Generated => "generated",
+            Self::Joined => "joined",
+            Self::Throttled(_) => "throttled",
+        }
+    }
+}
+
+/// Lock held while a day is aggregated on demand
+type RunLock = Arc<tokio::sync::Mutex<()>>;
+
+/// Runs of on-demand aggregations in progress on this replica
+static IN_FLIGHT: LazyLock<
+    std::sync::Mutex<HashMap<(AggregationScope, sqlx::types::time::Date), RunLock>>,
+> = LazyLock::new(Default::default);
+
+/// Aggregates `day` on demand, unless it is being aggregated already or was
+/// aggregated less than `aggregation.generate_cooldown_seconds` ago
+#[instrument(skip(db_settings, aggregation))]
+pub async fn generate(
+    db_settings: &DBSettings,
+    aggregation: &AggregationSettings,
+    scope: AggregationScope,
+    day: sqlx::types::time::Date,
+) -> Result<Generation> {
+    let run = IN_FLIGHT
+        .lock()
+        .expect("in-flight aggregations")
+        .entry((scope, day))
+        .or_default()
+        .clone();
+    let Ok(_running) = run.try_lock() else {
+        let _finished = run.lock().await;
+        return Ok(Generation::Joined);
+    };
+
+    let result = generate_unless_fresh(db_settings, aggregation, scope, day).await;
+    IN_FLIGHT
+        .lock()
+        .expect("in-flight aggregations")
+        .remove(&(scope, day));
+    result
+}
+
+async fn generate_unless_fresh(
+    db_settings: &DBSettings,
+    aggregation: &AggregationSettings,
+    scope: AggregationScope,
+    day: sqlx::types::time::Date,
+) -> Result<Generation> {
+    let pool = get_db_pool(db_settings).await;
+    let last_success = sqlx::query_scalar!(
+        r#"
+        SELECT
+          MAX(finished_at)
+        FROM
+          aggregation_runs
+        WHERE
+          day = $1
+          AND scope = $2
+          AND status = 'succeeded'"#,
+        day,
+        scope.as_str()
+    )
+    .fetch_one(&pool)
+    .await?;
+    if let Some(last_success) = last_success {
+        let age = (time::OffsetDateTime::now_utc() - last_success).whole_seconds();
+        let remaining = i64::try_from(aggregation.generate_cooldown_seconds)?.saturating_sub(age);
+        if remaining > 0 {
+            return Ok(Generation::Throttled(remaining.unsigned_abs()));
+        }
+    }
+
+    refresh_snapshot(db_settings, &aggregation.daily_strategy, day).await?;
+    match scope {
+        AggregationScope::Global => aggregate_stats(db_settings, day).await?,
+        AggregationScope::Context => aggregate_stats_by_context(db_settings, day).await?,
+    }
+    Ok(Generation::Generated)
+}
+
+/// Counts new, returning and churned homeservers of `day` from
+/// `homeserver_daily`, globally and per server context. The days before `day`
+/// must have been aggregated already.
+#[allow(clippy::too_many_lines)]
+#[instrument(skip(db_settings))]
+pub async fn aggregate_churn(
+    db_settings: &DBSettings,
+    churn_days: i64,
+    day: sqlx::types::time::Date,
+) -> Result<()> {
+    let churn_days = i32::try_from(churn_days).context("churn_days out of range")?;
+    let pool = get_db_pool(db_settings).await;
+    let counts = record_run(&pool, "churn", day, async {
+        let mut tx = pool.begin().await?;
+
+        // Only homeservers active on `day` or churning on it are of interest
+        let global = sqlx::query!(
+            r#"
+            WITH
+              events AS (
+                SELECT
+                  day,
+                  LAG(day) OVER w AS previous_day,
+                  LEAD(day) OVER w AS next_day
+                FROM
+                  homeserver_daily
+                WHERE
+                  homeserver IN (
+                    SELECT
+                      homeserver
+                    FROM
+                      homeserver_daily
+                    WHERE
+                      day IN ($1, $1::DATE - $2::INTEGER)
+                  )
+                WINDOW
+                  w AS (
+                    PARTITION BY
+                      homeserver
+                    ORDER BY
+                      day
+                  )
+              )
+            INSERT INTO
+              homeserver_churn (
+                day,
+                active_homeservers,
+                new_homeservers,
+                returning_homeservers,
+                churned_homeservers,
+                churn_days,
+                computed_at
+              )
+            SELECT
+              $1,
+              COUNT(*) FILTER (WHERE day = $1),
+              COUNT(*) FILTER (WHERE day = $1 AND previous_day IS NULL),
+              COUNT(*) FILTER (WHERE day = $1 AND day - previous_day > $2),
+              COUNT(*) FILTER (
+                WHERE
+                  day = $1::DATE - $2::INTEGER
+                  AND (next_day IS NULL OR next_day - day > $2)
+              ),
+              $2,
+              now()
+            FROM
+              events
+            ON CONFLICT (day) DO UPDATE
+            SET
+              active_homeservers = EXCLUDED.active_homeservers,
+              new_homeservers = EXCLUDED.new_homeservers,
+              returning_homeservers = EXCLUDED.returning_homeservers,
+              churned_homeservers = EXCLUDED.churned_homeservers,
+              churn_days = EXCLUDED.churn_days,
+              computed_at = EXCLUDED.computed_at;"#,
+            day,
+            churn_days
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not aggregate homeserver churn")?
+        .rows_affected();
+
+        sqlx::query!(
+            "DELETE FROM homeserver_churn_by_context WHERE day = $1",
+            day
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not clear homeserver_churn_by_context")?;
+
+        let by_context = sqlx::query!(
+            r#"
+            WITH
+              events AS (
+                SELECT
+                  day,
+                  server_context,
+                  LAG(day) OVER w AS previous_day,
+                  LEAD(day) OVER w AS next_day
+                FROM
+                  homeserver_daily
+                WHERE
+                  server_context IS NOT NULL
+                  AND homeserver IN (
+                    SELECT
+                      homeserver
+                    FROM
+                      homeserver_daily
+                    WHERE
+                      day IN ($1, $1::DATE - $2::INTEGER)
+                  )
+                WINDOW
+                  w AS (
+                    PARTITION BY
+                      homeserver,
+                      server_context
+                    ORDER BY
+                      day
+                  )
+              )
+            INSERT INTO
+              homeserver_churn_by_context (
+                day,
+                server_context,
+                active_homeservers,
+                new_homeservers,
+                returning_homeservers,
+                churned_homeservers,
+                churn_days,
+                computed_at
+              )
+            SELECT
+              $1,
+              server_context,
+              COUNT(*) FILTER (WHERE day = $1),
+              COUNT(*) FILTER (WHERE day = $1 AND previous_day IS NULL),
+              COUNT(*) FILTER (WHERE day = $1 AND day - previous_day > $2),
+              COUNT(*) FILTER (
+                WHERE
+                  day = $1::DATE - $2::INTEGER
+                  AND (next_day IS NULL OR next_day - day > $2)
+              ),
+              $2,
+              now()
+            FROM
+              events
+            WHERE
+              day IN ($1, $1::DATE - $2::INTEGER)
+            GROUP BY
+              server_context;"#,
+            day,
+            churn_days
+        )
+        .execute(&mut *tx)
+        .await
+        .context("could not aggregate homeserver churn by context")?
+        .rows_affected();
+
+        let report_count = daily_report_count(&mut tx, day).await?;
+        tx.commit().await?;
+
+        Ok(RunCounts {
+            rows_written: global + by_context,
+            report_count,
+        })
+    })
+    .await?;
+
+    info!(
+        "Aggregated homeserver churn for {day} with {} rows generated successfully",
+        counts.rows_written
+    );
+
+    Ok(())
+}
+
+pub async fn get_homeserver_churn(
+    db_settings: &DBSettings,
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+) -> Result<Vec<HomeserverChurn>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        HomeserverChurn,
+        "SELECT * FROM homeserver_churn WHERE day BETWEEN $1 AND $2 ORDER BY day",
+        from,
+        to
+    )
+    .fetch_all(&pool)
+    .await?)
+}
+
+pub async fn get_homeserver_churn_by_context(
+    db_settings: &DBSettings,
+    server_context: String,
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+) -> Result<Vec<HomeserverChurnByContext>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        HomeserverChurnByContext,
+        r#"
+        SELECT
+          *
+        FROM
+          homeserver_churn_by_context
+        WHERE
+          server_context = $1
+          AND day BETWEEN $2 AND $3
+        ORDER BY
+          day"#,
+        server_context,
+        from,
+        to
+    )
+    .fetch_all(&pool)
+    .await?)
+}
+
+pub async fn get_aggregated_stats(
+    db_settings: &DBSettings,
+    day: sqlx::types::time::Date,
+) -> Result<Option<AggregatedStats>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        AggregatedStats,
+        "SELECT * FROM aggregated_stats WHERE day = $1",
+        day
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
+
+pub async fn get_aggregated_stats_by_context(
+    db_settings: &DBSettings,
+    day: sqlx::types::time::Date,
+    server_context: String,
+) -> Result<Option<AggregatedStatsByContext>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        AggregatedStatsByContext,
+        "SELECT * FROM aggregated_stats_by_context WHERE day = $1 AND server_context = $2",
+        day,
+        server_context
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
+
+pub async fn get_aggregated_stats_range(
+    db_settings: &DBSettings,
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+) -> Result<Vec<AggregatedStats>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        AggregatedStats,
+        "SELECT * FROM aggregated_stats WHERE day BETWEEN $1 AND $2 ORDER BY day",
+        from,
+        to
+    )
+    .fetch_all(&pool)
+    .await?)
+}
+
+pub async fn get_aggregated_stats_by_context_range(
+    db_settings: &DBSettings,
+    server_context: String,
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+) -> Result<Vec<AggregatedStatsByContext>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        AggregatedStatsByContext,
+        r#"
+        SELECT
+          *
+        FROM
+          aggregated_stats_by_context
+        WHERE
+          server_context = $1
+          AND day BETWEEN $2 AND $3
+        ORDER BY
+          day"#,
+        server_context,
+        from,
+        to
+    )
+    .fetch_all(&pool)
+    .await?)
+}
+
+/// Server contexts seen in `aggregated_stats_by_context`, ordered by name,
+/// along with the total number of contexts
+pub async fn get_contexts(
+    db_settings: &DBSettings,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<ContextSummary>, i64)> {
+    let pool = get_db_pool(db_settings).await;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(DISTINCT server_context) AS "total!" FROM aggregated_stats_by_context"#
+    )
+    .fetch_one(&pool)
+    .await?;
+    let contexts = sqlx::query_as!(
+        ContextSummary,
+        r#"
+        SELECT
+          seen.server_context AS "server_context!",
+          seen.first_day AS "first_day!",
+          seen.last_day AS "last_day!",
+          latest.daily_active_homeservers AS homeservers,
+          latest.total_users
+        FROM
+          (
+            SELECT
+              server_context,
+              MIN(day) AS first_day,
+              MAX(day) AS last_day
+            FROM
+              aggregated_stats_by_context
+            GROUP BY
+              server_context
+          ) seen
+          JOIN aggregated_stats_by_context latest ON latest.server_context = seen.server_context
+          AND latest.day = seen.last_day
+        ORDER BY
+          seen.server_context
+        LIMIT
+          $1
+        OFFSET
+          $2"#,
+        limit,
+        offset
+    )
+    .fetch_all(&pool)
+    .await?;
+    Ok((contexts, total))
+}
+
+/// Filters of the homeserver directory
+#[derive(Debug, Clone, Default)]
+pub struct HomeserverFilter {
+    pub status: Option<HomeserverStatus>,
+    pub server_context: Option<String>,
+    pub version: Option<String>,
+    /// Case insensitive part of the homeserver name
+    pub search: Option<String>,
+}
+
+/// Pushes the homeserver directory, derived from the latest report of every
+/// homeserver, as `directory` CTE along with the `WHERE` clause of `filter`
+fn push_homeserver_directory(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    filter: &HomeserverFilter,
+    stale_days: i32,
+    churn_days: i32,
+) {
+    builder.push(
+        r"
+        WITH
+          latest AS (
+            SELECT DISTINCT
+              ON (homeserver) homeserver,
+              local_timestamp,
+              user_agent,
+              server_context,
+              total_users,
+              total_nonbridged_users,
+              daily_active_users,
+              monthly_active_users
+            FROM
+              reports
+            WHERE
+              homeserver IS NOT NULL
+            ORDER BY
+              homeserver,
+              local_timestamp DESC NULLS LAST,
+              id DESC
+          ),
+          seen AS (
+            SELECT
+              homeserver,
+              MIN(local_timestamp) AS first_report,
+              COUNT(*) AS report_count
+            FROM
+              reports
+            WHERE
+              homeserver IS NOT NULL
+            GROUP BY
+              homeserver
+          ),
+          directory AS (
+            SELECT
+              latest.homeserver,
+              seen.first_report,
+              latest.local_timestamp AS last_report,
+              seen.report_count,
+              SUBSTRING(latest.user_agent FROM 'Synapse/([^ ]+)') AS version,
+              latest.user_agent,
+              latest.server_context,
+              latest.total_users,
+              latest.total_nonbridged_users,
+              latest.daily_active_users,
+              latest.monthly_active_users,
+              CASE
+                WHEN latest.local_timestamp >= now() - make_interval(days => ",
+    );
+    builder.push_bind(stale_days);
+    builder.push(
+        r") THEN 'active'
+                WHEN latest.local_timestamp >= now() - make_interval(days => ",
+    );
+    builder.push_bind(churn_days);
+    builder.push(
+        r") THEN 'stale'
+                ELSE 'churned'
+              END AS status
+            FROM
+              latest
+              JOIN seen USING (homeserver)
+          )
+        SELECT
+          *
+        FROM
+          directory
+        WHERE
+          TRUE",
+    );
+    if let Some(status) = filter.status {
+        builder.push(" AND status = ").push_bind(status);
+    }
+    if let Some(server_context) = &filter.server_context {
+        builder
+            .push(" AND server_context = ")
+            .push_bind(server_context.clone());
+    }
+    if let Some(version) = &filter.version {
+        builder.push(" AND version = ").push_bind(version.clone());
+    }
+    if let Some(search) = &filter.search {
+        builder
+            .push(" AND STRPOS(LOWER(homeserver), LOWER(")
+            .push_bind(search.clone())
+            .push(")) > 0");
+    }
+}
+
+/// Homeservers matching `filter`, along with their total number
+pub async fn get_homeservers(
+    db_settings: &DBSettings,
+    aggregation: &AggregationSettings,
+    filter: &HomeserverFilter,
+    (sort, order): (HomeserverSort, SortOrder),
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<HomeserverSummary>, i64)> {
+    let pool = get_db_pool(db_settings).await;
+    let stale_days = i32::try_from(aggregation.stale_days).context("stale_days out of range")?;
+    let churn_days = i32::try_from(aggregation.churn_days).context("churn_days out of range")?;
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM (");
+    push_homeserver_directory(&mut count, filter, stale_days, churn_days);
+    count.push(") matching");
+    let total = count
+        .build_query_scalar::<i64>()
+        .fetch_one(&pool)
+        .await
+        .context("failed counting homeservers")?;
+
+    let mut query = QueryBuilder::new("");
+    push_homeserver_directory(&mut query, filter, stale_days, churn_days);
+    // The sort column comes from a fixed list, never from user input
+    query.push(format!(
+        " ORDER BY {} {} NULLS LAST, homeserver LIMIT ",
+        sort.column(),
+        order.sql()
+    ));
+    query.push_bind(limit).push(" OFFSET ").push_bind(offset);
+    let homeservers = query
+        .build_query_as::<HomeserverSummary>()
+        .fetch_all(&pool)
+        .await
+        .context("failed listing homeservers")?;
+
+    Ok((homeservers, total))
+}
+
+/// Daily values and report counts of `homeserver` for every day of the range
+/// with reports
+pub async fn get_homeserver_history(
+    db_settings: &DBSettings,
+    homeserver: &str,
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+) -> Result<Vec<HomeserverDay>> {
+    let pool = get_db_pool(db_settings).await;
+    let columns = DAILY_METRICS
+        .iter()
+        .chain(DAILY_LATEST_FIELDS)
+        .map(|column| format!("hd.{column}"))
+        .collect::<Vec<_>>();
+    let query = format!(
+        r"
+        SELECT
+          *
+        FROM
+          (
+            SELECT
+              days.day::DATE AS day,
+              (
+                SELECT
+                  COUNT(*)
+                FROM
+                  reports
+                WHERE
+                  homeserver = $1
+                  AND local_timestamp >= days.day::DATE
+                  AND local_timestamp < days.day::DATE + 1
+              ) AS report_count,
+              hd.report_id,
+              hd.local_timestamp,
+              hd.daily_strategy,
+              {columns}
+            FROM
+              generate_series($2::DATE, $3::DATE, INTERVAL '1 day') AS days (day)
+              LEFT JOIN homeserver_daily hd ON hd.homeserver = $1
+              AND hd.day = days.day::DATE
+          ) history
+        WHERE
+          report_count > 0
+          OR report_id IS NOT NULL
+        ORDER BY
+          day",
+        columns = columns.join(",\n              "),
+    );
+
+    sqlx::query_as::<_, HomeserverDay>(&query)
+        .bind(homeserver)
+        .bind(from)
+        .bind(to)
+        .fetch_all(&pool)
+        .await
+        .context("failed getting homeserver history")
+}
+
+/// Checks that `metric` is one of [`DAILY_METRICS`], as it is interpolated
+/// into the SQL as a column name
+fn check_daily_metric(metric: &str) -> Result<()> {
+    anyhow::ensure!(DAILY_METRICS.contains(&metric), "unknown metric {metric:?}");
+    Ok(())
+}
+
+/// Ranks homeservers by `function` over their daily values of `metric`, which
+/// must be one of [`DAILY_METRICS`]. Returns the top `limit` homeservers and
+/// the total over all of them.
+pub async fn get_homeserver_ranking(
+    db_settings: &DBSettings,
+    metric: &str,
+    function: AggregateFunction,
+    (from, to): (sqlx::types::time::Date, sqlx::types::time::Date),
+    server_context: Option<&str>,
+    limit: i64,
+) -> Result<(Vec<RankedHomeserver>, Option<f64>)> {
+    check_daily_metric(metric)?;
+    let pool = get_db_pool(db_settings).await;
+
+    let query = format!(
+        r"
+        WITH
+          per_homeserver AS (
+            SELECT
+              homeserver,
+              {function}({metric})::DOUBLE PRECISION AS value
+            FROM
+              homeserver_daily
+            WHERE
+              day BETWEEN $1 AND $2
+              AND ($3::TEXT IS NULL OR server_context = $3)
+            GROUP BY
+              homeserver
+          )
+        SELECT
+          ROW_NUMBER() OVER (ORDER BY value DESC, homeserver) AS rank,
+          homeserver,
+          value,
+          value / NULLIF(SUM(value) OVER (), 0) AS share,
+          SUM(value) OVER () AS total
+        FROM
+          per_homeserver
+        WHERE
+          value IS NOT NULL
+        ORDER BY
+          rank
+        LIMIT
+          $4",
+        function = function.sql(),
+    );
+    let rows = sqlx::query(&query)
+        .bind(from)
+        .bind(to)
+        .bind(server_context)
+        .bind(limit)
+        .fetch_all(&pool)
+        .await
+        .context("failed ranking homeservers")?;
+
+    let total = rows
+        .first()
+        .map(|row| row.try_get::<Option<f64>, _>("total"))
+        .transpose()?
+        .flatten();
+    let homeservers = rows
+        .iter()
+        .map(RankedHomeserver::from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((homeservers, total))
+}
+
+/// Parameters of [`query_homeserver_daily`]
+#[derive(Debug, Clone)]
+pub struct GroupQuery<'a> {
+    pub dimension: Dimension,
+    /// One of [`DAILY_METRICS`]
+    pub metric: &'a str,
+    pub function: AggregateFunction,
+    pub from: sqlx::types::time::Date,
+    pub to: sqlx::types::time::Date,
+    pub server_context: Option<&'a str>,
+}
+
+/// Groups the daily values of homeservers by a dimension, returning at most
+/// `max_groups` groups and whether there were more. The query is cancelled
+/// after `timeout_ms`, see [`is_query_canceled`].
+pub async fn query_homeserver_daily(
+    db_settings: &DBSettings,
+    query: &GroupQuery<'_>,
+    max_groups: i64,
+    timeout_ms: u64,
+) -> Result<(Vec<QueryGroup>, bool)> {
+    let metric = query.metric;
+    check_daily_metric(metric)?;
+    let pool = get_db_pool(db_settings).await;
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!("SET LOCAL statement_timeout = {timeout_ms}"))
+        .execute(&mut *tx)
+        .await?;
+
+    let sql = format!(
+        r"
+        SELECT
+          {dimension} AS key,
+          {function}({metric})::DOUBLE PRECISION AS value,
+          COUNT(DISTINCT homeserver) AS homeservers
+        FROM
+          homeserver_daily
+        WHERE
+          day BETWEEN $1 AND $2
+          AND ($3::TEXT IS NULL OR server_context = $3)
+        GROUP BY
+          1
+        ORDER BY
+          value DESC NULLS LAST,
+          key
+        LIMIT
+          $4",
+        dimension = query.dimension.sql(),
+        function = query.function.sql(),
+    );
+    let mut groups = sqlx::query_as::<_, QueryGroup>(&sql)
+        .bind(query.from)
+        .bind(query.to)
+        .bind(query.server_context)
+        .bind(max_groups + 1)
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    let truncated = groups.len() > usize::try_from(max_groups).unwrap_or(usize::MAX);
+    groups.truncate(usize::try_from(max_groups).unwrap_or(usize::MAX));
+    Ok((groups, truncated))
+}
+
+/// Whether `err` is caused by a statement timeout or cancellation
+pub fn is_query_canceled(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<sqlx::Error>()
+        .and_then(|err| err.as_database_error())
+        .and_then(sqlx::error::DatabaseError::code)
+        .is_some_and(|code| code == "57014")
+}
+
+/// Global stats of the latest aggregated day
+pub async fn get_latest_aggregated_stats(
+    db_settings: &DBSettings,
+) -> Result<Option<AggregatedStats>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        AggregatedStats,
+        "SELECT * FROM aggregated_stats ORDER BY day DESC LIMIT 1"
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
+
+/// Stats of all server contexts of the latest aggregated day
+pub async fn get_latest_aggregated_stats_by_context(
+    db_settings: &DBSettings,
+) -> Result<Vec<AggregatedStatsByContext>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        AggregatedStatsByContext,
+        r#"
+        SELECT
+          *
+        FROM
+          aggregated_stats_by_context
+        WHERE
+          day = (SELECT MAX(day) FROM aggregated_stats_by_context)
+        ORDER BY
+          server_context"#
+    )
+    .fetch_all(&pool)
+    .await?)
+}
+
+/// How outdated `api_keys.last_used_at` may get, so that authenticated reads
+/// don't each write the key too
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+/// Active API key with the given SHA-256 hash, recording that it was used
+pub async fn use_api_key(db_settings: &DBSettings, key_hash: &[u8]) -> Result<Option<ApiKey>> {
+    let pool = get_db_pool(db_settings).await;
+    let key = sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT
+          id,
+          label,
+          scopes,
+          created_at,
+          expires_at,
+          revoked_at,
+          last_used_at
+        FROM
+          api_keys
+        WHERE
+          key_hash = $1
+          AND revoked_at IS NULL
+          AND (
+            expires_at IS NULL
+            OR expires_at > now()
+          )"#,
+        key_hash
+    )
+    .fetch_optional(&pool)
+    .await?;
+
+    let outdated =
+        time::OffsetDateTime::now_utc() - time::Duration::seconds(LAST_USED_RESOLUTION_SECONDS);
+    if let Some(key) = &key
+        && key.last_used_at.is_none_or(|used| used < outdated)
+    {
+        sqlx::query!(
+            r#"
+            UPDATE api_keys
+            SET
+              last_used_at = now()
+            WHERE
+              id = $1
+              AND (
+                last_used_at IS NULL
+                OR last_used_at < $2
+              )"#,
+            key.id,
+            outdated
+        )
+        .execute(&pool)
+        .await?;
+    }
+    Ok(key)
+}
+
+/// API keys ordered by creation, including the expired and revoked ones
+pub async fn get_api_keys(
+    db_settings: &DBSettings,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<ApiKey>, i64)> {
+    let pool = get_db_pool(db_settings).await;
+    let total = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "total!" FROM api_keys"#)
+        .fetch_one(&pool)
+        .await?;
+    let keys = sqlx::query_as!(
+        ApiKey,
+        r#"
+        SELECT
+          id,
+          label,
+          scopes,
+          created_at,
+          expires_at,
+          revoked_at,
+          last_used_at
+        FROM
+          api_keys
+        ORDER BY
+          id
+        LIMIT
+          $1
+        OFFSET
+          $2"#,
+        limit,
+        offset
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok((keys, total))
+}
+
+pub async fn create_api_key(
+    db_settings: &DBSettings,
+    key: &NewApiKey,
+    key_hash: &[u8],
+) -> Result<ApiKey> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        ApiKey,
+        r#"
+        INSERT INTO
+          api_keys (label, key_hash, scopes, expires_at)
+        VALUES
+          ($1, $2, $3, $4)
+        RETURNING
+          id,
+          label,
+          scopes,
+          created_at,
+          expires_at,
+          revoked_at,
+          last_used_at"#,
+        key.label,
+        key_hash,
+        &key.scopes,
+        key.expires_at
+    )
+    .fetch_one(&pool)
+    .await?)
+}
+
+/// Replaces the hash of an API key, `None` if there is no such key which
+/// hasn't been revoked
+pub async fn rotate_api_key(
+    db_settings: &DBSettings,
+    id: i64,
+    key_hash: &[u8],
+) -> Result<Option<ApiKey>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        ApiKey,
+        r#"
+        UPDATE api_keys
+        SET
+          key_hash = $2
+        WHERE
+          id = $1
+          AND revoked_at IS NULL
+        RETURNING
+          id,
+          label,
+          scopes,
+          created_at,
+          expires_at,
+          revoked_at,
+          last_used_at"#,
+        id,
+        key_hash
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
+
+/// Sets the expiry of an API key, `None` if there is no such key
+pub async fn expire_api_key(
+    db_settings: &DBSettings,
+    id: i64,
+    expires_at: Option<time::OffsetDateTime>,
+) -> Result<Option<ApiKey>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        ApiKey,
+        r#"
+        UPDATE api_keys
+        SET
+          expires_at = $2
+        WHERE
+          id = $1
+        RETURNING
+          id,
+          label,
+          scopes,
+          created_at,
+          expires_at,
+          revoked_at,
+          last_used_at"#,
+        id,
+        expires_at
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
+
+/// Revokes an API key for good, `None` if there is no such key. Revoking a
+/// key again keeps the time it was first revoked.
+pub async fn revoke_api_key(db_settings: &DBSettings, id: i64) -> Result<Option<ApiKey>> {
+    let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        ApiKey,
+        r#"
+        UPDATE api_keys
+        SET
+          revoked_at = COALESCE(revoked_at, now())
+        WHERE
+          id = $1
+        RETURNING
+          id,
+          label,
+          scopes,
+          created_at,
+          expires_at,
+          revoked_at,
+          last_used_at"#,
+        id
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
+
+/// Saves a report, scoring it in the same transaction so that no aggregation
+/// sees an anomalous report before it is flagged. Reports of homeservers which
+/// had their data erased and their reports refused are dropped, returning
+/// `None`.
+async fn store_report(
+    pool: &PgPool,
+    report: &Report,
+    anomaly: &AnomalySettings,
+) -> Result<Option<i64>> {
+    let mut tx = pool.begin().await?;
+    if let Some(homeserver) = &report.homeserver {
+        let refused = sqlx::query_scalar!(
+            r#"
+            SELECT
+              EXISTS (
+                SELECT
+                FROM
+                  homeserver_tombstones
+                WHERE
+                  homeserver = $1
+                  AND refuse_reports
+              ) AS "refused!""#,
+            homeserver
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if refused {
+            metrics::REPORTS_REJECTED
+                .with_label_values(&["erased"])
+                .inc();
+            return Ok(None);
         }
     }
+
+    let id = save_report(&mut tx, report).await?;
+    if anomaly.enabled && !report.excluded && report.homeserver.is_some() {
+        score_report(&mut tx, id, anomaly).await?;
+    }
+    tx.commit().await?;
+    Ok(Some(id))
 }
 
-pub async fn connect_pg_gracefully(url: &str) -> Result<PgPool> {
-    PgPool::connect(url)
+/// Scores a saved report against the `homeserver_daily` values of its
+/// homeserver within `history_days` before its day, as the ratio of each
+/// summed metric to its median. Reports with a metric above its threshold are
+/// flagged, which keeps them out of the aggregation until reviewed.
+#[instrument(skip(conn, anomaly))]
+async fn score_report(conn: &mut PgConnection, id: i64, anomaly: &AnomalySettings) -> Result<()> {
+    let history_days = i32::try_from(anomaly.history_days).context("history_days out of range")?;
+    let medians = AGGREGATED_METRICS
+        .iter()
+        .map(|metric| {
+            format!("PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY hd.{metric}) AS {metric}")
+        })
+        .collect::<Vec<_>>();
+    let ratios = AGGREGATED_METRICS
+        .iter()
+        .map(|metric| {
+            format!(
+                "CASE WHEN history.{metric} IS NOT NULL \
+                 THEN r.{metric} / GREATEST(history.{metric}, $3) END AS {metric}"
+            )
+        })
+        .collect::<Vec<_>>();
+    let query = format!(
+        r"
+        WITH
+          history AS (
+            SELECT
+              COUNT(*) AS days,
+              {medians}
+            FROM
+              reports r
+              JOIN homeserver_daily hd ON hd.homeserver = r.homeserver
+            WHERE
+              r.id = $1
+              AND hd.day >= r.local_timestamp::DATE - $2::INT
+              AND hd.day < r.local_timestamp::DATE
+          )
+        SELECT
+          history.days,
+          {ratios}
+        FROM
+          reports r,
+          history
+        WHERE
+          r.id = $1",
+        medians = medians.join(",\n              "),
+        ratios = ratios.join(",\n          "),
+    );
+    let row = sqlx::query(&query)
+        .bind(id)
+        .bind(history_days)
+        .bind(anomaly.min_baseline)
+        .fetch_one(&mut *conn)
         .await
-        .context("failed connecting to PostgreSQL server.")
-}
+        .context("could not score report")?;
+    if row.try_get::<i64, _>("days")? < anomaly.min_history_days {
+        return Ok(());
+    }
 
-async fn connect_pg(url: &str) -> PgPool {
-    let pool = match connect_pg_gracefully(url).await {
-        Ok(pool) => pool,
-        Err(err) => {
-            log::error!("{err:?}");
-            process::exit(-1);
+    let mut score = None::<f64>;
+    let mut anomalies = BTreeMap::new();
+    for metric in AGGREGATED_METRICS {
+        let Some(ratio) = row.try_get::<Option<f64>, _>(*metric)? else {
+            continue;
+        };
+        score = Some(score.map_or(ratio, |score| score.max(ratio)));
+        if ratio > anomaly.threshold_for(metric) {
+            anomalies.insert(*metric, ratio);
         }
-    };
-
-    if let Err(err) = sqlx::migrate!()
-        .run(&pool)
-        .await
-        .context("failed to run migrations")
-    {
-        log::error!("{err:?}");
-        process::exit(-1);
+    }
+    let flagged = !anomalies.is_empty();
+    if flagged {
+        metrics::REPORTS_FLAGGED.inc();
+        log::warn!("Flagged report {id} as anomalous: {anomalies:?}");
     }
 
-    pool
+    sqlx::query!(
+        r#"
+        UPDATE reports
+        SET
+          anomaly_score = $2,
+          anomalies = $3,
+          review_status = $4
+        WHERE
+          id = $1"#,
+        id,
+        score,
+        flagged.then(|| sqlx::types::Json(&anomalies)) as _,
+        flagged.then_some(ReviewStatus::Flagged) as _,
+    )
+    .execute(conn)
+    .await
+    .context("could not record anomaly score")?;
+    Ok(())
 }
 
-pub async fn get_db_pool(DBSettings { url }: &DBSettings) -> PgPool {
-    use tokio::sync::OnceCell;
-    static PG_POOL_CELL: OnceCell<PgPool> = OnceCell::const_new();
+/// Reports waiting for review, oldest first
+pub async fn get_flagged_reports(
+    db_settings: &DBSettings,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<FlaggedReport>, i64)> {
+    let pool = get_db_pool(db_settings).await;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "total!" FROM reports WHERE review_status = 'flagged'"#
+    )
+    .fetch_one(&pool)
+    .await?;
+    let reports = sqlx::query_as!(
+        FlaggedReport,
+        r#"
+        SELECT
+          id,
+          homeserver,
+          server_context,
+          local_timestamp,
+          anomaly_score,
+          anomalies,
+          review_status AS "review_status!: ReviewStatus",
+          reviewed_at
+        FROM
+          reports
+        WHERE
+          review_status = 'flagged'
+        ORDER BY
+          id
+        LIMIT
+          $1
+        OFFSET
+          $2"#,
+        limit,
+        offset
+    )
+    .fetch_all(&pool)
+    .await?;
 
-    PG_POOL_CELL
-        .get_or_init(|| async { connect_pg(url).await })
-        .await
-        .clone()
+    Ok((reports, total))
 }
 
-#[allow(clippy::too_many_lines)]
-#[instrument(skip(db_settings))]
-pub async fn aggregate_stats(db_settings: &DBSettings, day: sqlx::types::time::Date) -> Result<()> {
+/// Records the review of a flagged report, `None` if there is no such report
+/// waiting for review
+pub async fn review_report(
+    db_settings: &DBSettings,
+    id: i64,
+    status: ReviewStatus,
+) -> Result<Option<FlaggedReport>> {
     let pool = get_db_pool(db_settings).await;
+    Ok(sqlx::query_as!(
+        FlaggedReport,
+        r#"
+        UPDATE reports
+        SET
+          review_status = $2,
+          reviewed_at = now()
+        WHERE
+          id = $1
+          AND review_status = 'flagged'
+        RETURNING
+          id,
+          homeserver,
+          server_context,
+          local_timestamp,
+          anomaly_score,
+          anomalies,
+          review_status AS "review_status!: ReviewStatus",
+          reviewed_at"#,
+        id,
+        status as _
+    )
+    .fetch_optional(&pool)
+    .await?)
+}
 
-    let _ = sqlx::query!(
+/// Deletes the reports of `homeserver` within the requested days, along with
+/// their `homeserver_daily` rows, and records a tombstone telling whether its
+/// reports are refused from then on. The days of the deleted reports still
+/// need to be aggregated again.
+pub async fn erase_homeserver(
+    db_settings: &DBSettings,
+    homeserver: &str,
+    request: &ErasureRequest,
+) -> Result<Erasure> {
+    let pool = get_db_pool(db_settings).await;
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
         r#"
-        INSERT INTO
-          aggregated_stats (
-            day,
-            total_users,
-            total_nonbridged_users,
-            total_room_count,
-            daily_active_users,
-            daily_active_rooms,
-            daily_messages,
-            daily_sent_messages,
-            daily_active_e2ee_rooms,
-            daily_e2ee_messages,
-            daily_sent_e2ee_messages,
-            monthly_active_users,
-            r30_users_all,
-            r30_users_android,
-            r30_users_ios,
-            r30_users_electron,
-            r30_users_web,
-            r30v2_users_all,
-            r30v2_users_android,
-            r30v2_users_ios,
-            r30v2_users_electron,
-            r30v2_users_web,
-            daily_user_type_native,
-            daily_user_type_bridged,
-            daily_user_type_guest,
-            daily_active_homeservers
+        DELETE FROM homeserver_daily
+        WHERE
+          homeserver = $1
+          AND ($2::DATE IS NULL OR day >= $2)
+          AND ($3::DATE IS NULL OR day <= $3)"#,
+        homeserver,
+        request.from,
+        request.to
+    )
+    .execute(&mut *tx)
+    .await
+    .context("could not delete daily values")?;
+
+    let deleted = sqlx::query!(
+        r#"
+        WITH
+          deleted AS (
+            DELETE FROM reports
+            WHERE
+              homeserver = $1
+              AND ($2::DATE IS NULL OR local_timestamp >= $2::DATE)
+              AND ($3::DATE IS NULL OR local_timestamp < $3::DATE + 1)
+            RETURNING
+              local_timestamp,
+              server_context
           )
         SELECT
-          local_timestamp:: DATE,
-          SUM(total_users),
-          SUM(total_nonbridged_users),
-          SUM(total_room_count),
-          SUM(daily_active_users),
-          SUM(daily_active_rooms),
-          SUM(daily_messages),
-          SUM(daily_sent_messages),
-          SUM(daily_active_e2ee_rooms),
-          SUM(daily_e2ee_messages),
-          SUM(daily_sent_e2ee_messages),
-          SUM(monthly_active_users),
-          SUM(r30_users_all),
-          SUM(r30_users_android),
-          SUM(r30_users_ios),
-          SUM(r30_users_electron),
-          SUM(r30_users_web),
-          SUM(r30v2_users_all),
-          SUM(r30v2_users_android),
-          SUM(r30v2_users_ios),
-          SUM(r30v2_users_electron),
-          SUM(r30v2_users_web),
-          SUM(daily_user_type_native),
-          SUM(daily_user_type_bridged),
-          SUM(daily_user_type_guest),
-          COUNT(homeserver)
-        FROM
-          (
-            SELECT
-              DISTINCT ON (homeserver, local_timestamp:: DATE) *
-            FROM
-              reports
+          COUNT(*) AS "reports!",
+          ARRAY_AGG(DISTINCT local_timestamp::DATE) FILTER (
             WHERE
-              local_timestamp:: DATE = $1
-            ORDER BY
-              homeserver,
-              local_timestamp:: DATE,
-              local_timestamp DESC
-          ) as _
-        GROUP BY
-          local_timestamp:: DATE ON CONFLICT (day) DO
+              local_timestamp IS NOT NULL
+          ) AS days,
+          ARRAY_AGG(DISTINCT server_context) FILTER (
+            WHERE
+              server_context IS NOT NULL
+          ) AS server_contexts
+        FROM
+          deleted"#,
+        homeserver,
+        request.from,
+        request.to
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .context("could not delete reports")?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO
+          homeserver_tombstones (homeserver, refuse_reports)
+        VALUES
+          ($1, $2)
+        ON CONFLICT (homeserver) DO
         UPDATE
         SET
-          total_users = excluded.total_users,
-          total_nonbridged_users = excluded.total_nonbridged_users,
-          total_room_count = excluded.total_room_count,
-          daily_active_users = excluded.daily_active_users,
-          daily_active_rooms = excluded.daily_active_rooms,
-          daily_messages = excluded.daily_messages,
-          daily_sent_messages = excluded.daily_sent_messages,
-          daily_active_e2ee_rooms = excluded.daily_active_e2ee_rooms,
-          daily_e2ee_messages = excluded.daily_e2ee_messages,
-          daily_sent_e2ee_messages = excluded.daily_sent_e2ee_messages,
-          monthly_active_users = excluded.monthly_active_users,
-          r30_users_all = excluded.r30_users_all,
-          r30_users_android = excluded.r30_users_android,
-          r30_users_ios = excluded.r30_users_ios,
-          r30_users_electron = excluded.r30_users_electron,
-          r30_users_web = excluded.r30_users_web,
-          r30v2_users_all = excluded.r30_users_all,
-          r30v2_users_android = excluded.r30_users_android,
-          r30v2_users_ios = excluded.r30_users_ios,
-          r30v2_users_electron = excluded.r30_users_electron,
-          r30v2_users_web = excluded.r30_users_web,
-          daily_user_type_native = excluded.daily_user_type_native,
-          daily_user_type_bridged = excluded.daily_user_type_bridged,
-          daily_user_type_guest = excluded.daily_user_type_guest,
-          daily_active_homeservers = excluded.daily_active_homeservers;"#,
-        day
+          erased_at = now(),
+          refuse_reports = excluded.refuse_reports"#,
+        homeserver,
+        request.refuse_reports
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await
-    .context("could not aggregate stats")?;
+    .context("could not record tombstone")?;
 
-    let _ = sqlx::query!(
+    // Until aggregated again, so that the scheduled jobs pick up where a
+    // failed erasure left off
+    let days = deleted.days.unwrap_or_default();
+    sqlx::query!(
         r#"
-          WITH totals AS (
-              SELECT
-                  day,
-                  SUM(daily_messages) OVER (
-                      ORDER BY day
-                      ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
-                  ) AS total_messages,
-                  SUM(daily_e2ee_messages) OVER (
-                      ORDER BY day
-                      ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
-                  ) AS total_e2ee_messages
-              FROM aggregated_stats
-          )
-          UPDATE aggregated_stats t
-          SET
-              total_messages = totals.total_messages,
-              total_e2ee_messages = totals.total_e2ee_messages
-          FROM totals
-          WHERE t.day = totals.day AND t.day = $1;
-"#,
-        day
+        INSERT INTO
+          pending_aggregations (day)
+        SELECT
+          UNNEST($1::DATE[])
+        ON CONFLICT (day) DO NOTHING"#,
+        &days
     )
-    .execute(&pool)
+    .execute(&mut *tx)
+    .await
+    .context("could not record pending aggregations")?;
+    tx.commit().await?;
+
+    Ok(Erasure {
+        homeserver: homeserver.to_owned(),
+        from: request.from,
+        to: request.to,
+        reports_deleted: deleted.reports,
+        days,
+        pending_days: Vec::new(),
+        server_contexts: deleted.server_contexts.unwrap_or_default(),
+        refuse_reports: request.refuse_reports,
+    })
+}
+
+/// Days whose reports have been erased, but which are yet to be aggregated
+/// again
+pub async fn pending_aggregations(
+    db_settings: &DBSettings,
+) -> Result<Vec<sqlx::types::time::Date>> {
+    let pool = get_db_pool(db_settings).await;
+    sqlx::query_scalar!(
+        r#"
+        SELECT
+          day
+        FROM
+          pending_aggregations
+        ORDER BY
+          day"#
+    )
+    .fetch_all(&pool)
     .await
-    .context("could not add total_messages and total_e2ee_messages to aggregated_stats")?;
+    .context("could not get pending aggregations")
+}
 
-    info!("Aggregated stats for {day} generated successfully");
+/// Aggregates `days` again, along with the running message totals after them
+/// and the churn on them and `churn_days` later. Each day is cleared from the
+/// pending aggregations once done, churn of days still to come is left to
+/// the scheduled jobs.
+pub async fn aggregate_pending(
+    db_settings: &DBSettings,
+    aggregation: &AggregationSettings,
+    days: &[sqlx::types::time::Date],
+) -> Result<()> {
+    let today = time::OffsetDateTime::now_utc().date();
+    for &day in days {
+        aggregate_day(db_settings, &aggregation.daily_strategy, day).await?;
+        refresh_message_totals(db_settings, day).await?;
+        let churned = day.checked_add(time::Duration::days(aggregation.churn_days));
+        for churn_day in [Some(day), churned].into_iter().flatten() {
+            if churn_day <= today {
+                aggregate_churn(db_settings, aggregation.churn_days, churn_day).await?;
+            }
+        }
 
+        let pool = get_db_pool(db_settings).await;
+        sqlx::query!("DELETE FROM pending_aggregations WHERE day = $1", day)
+            .execute(&pool)
+            .await
+            .context("could not clear pending aggregation")?;
+    }
     Ok(())
 }
 
-#[allow(clippy::too_many_lines)]
-#[instrument(skip(db_settings))]
-pub async fn aggregate_stats_by_context(
+/// Recomputes the running message totals of the aggregated stats from `day`
+/// on, which aggregating a day only does for that day
+pub async fn refresh_message_totals(
     db_settings: &DBSettings,
     day: sqlx::types::time::Date,
 ) -> Result<()> {
     let pool = get_db_pool(db_settings).await;
-
-    let _ = sqlx::query!(
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
         r#"
-        INSERT INTO
-          aggregated_stats_by_context (
-            day,
-            server_context,
-            total_users,
-            total_nonbridged_users,
-            total_room_count,
-            daily_active_users,
-            daily_active_rooms,
-            daily_messages,
-            daily_sent_messages,
-            daily_active_e2ee_rooms,
-            daily_e2ee_messages,
-            daily_sent_e2ee_messages,
-            monthly_active_users,
-            r30_users_all,
-            r30_users_android,
-            r30_users_ios,
-            r30_users_electron,
-            r30_users_web,
-            r30v2_users_all,
-            r30v2_users_android,
-            r30v2_users_ios,
-            r30v2_users_electron,
-            r30v2_users_web,
-            daily_user_type_native,
-            daily_user_type_bridged,
-            daily_user_type_guest,
-            daily_active_homeservers
-          )
-        SELECT
-          local_timestamp:: DATE,
-          server_context,
-          SUM(total_users),
-          SUM(total_nonbridged_users),
-          SUM(total_room_count),
-          SUM(daily_active_users),
-          SUM(daily_active_rooms),
-          SUM(daily_messages),
-          SUM(daily_sent_messages),
-          SUM(daily_active_e2ee_rooms),
-          SUM(daily_e2ee_messages),
-          SUM(daily_sent_e2ee_messages),
-          SUM(monthly_active_users),
-          SUM(r30_users_all),
-          SUM(r30_users_android),
-          SUM(r30_users_ios),
-          SUM(r30_users_electron),
-          SUM(r30_users_web),
-          SUM(r30v2_users_all),
-          SUM(r30v2_users_android),
-          SUM(r30v2_users_ios),
-          SUM(r30v2_users_electron),
-          SUM(r30v2_users_web),
-          SUM(daily_user_type_native),
-          SUM(daily_user_type_bridged),
-          SUM(daily_user_type_guest),
-          COUNT(homeserver)
-        FROM
-          (
+        WITH
+          totals AS (
             SELECT
-              DISTINCT ON (homeserver, local_timestamp:: DATE) *
+              day,
+              SUM(daily_messages) OVER (
+                ORDER BY day
+                ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+              ) AS total_messages,
+              SUM(daily_e2ee_messages) OVER (
+                ORDER BY day
+                ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+              ) AS total_e2ee_messages
             FROM
-              reports
-            WHERE
-              local_timestamp:: DATE = $1
-            ORDER BY
-              homeserver,
-              local_timestamp:: DATE,
-              local_timestamp DESC
-          ) as _
-        WHERE server_context IS NOT NULL
-        GROUP BY
-          server_context,
-          local_timestamp:: DATE
-        ON CONFLICT (day, server_context) DO
-        UPDATE
+              aggregated_stats
+          )
+        UPDATE aggregated_stats t
         SET
-          total_users = excluded.total_users,
-          total_nonbridged_users = excluded.total_nonbridged_users,
-          total_room_count = excluded.total_room_count,
-          daily_active_users = excluded.daily_active_users,
-          daily_active_rooms = excluded.daily_active_rooms,
-          daily_messages = excluded.daily_messages,
-          daily_sent_messages = excluded.daily_sent_messages,
-          daily_active_e2ee_rooms = excluded.daily_active_e2ee_rooms,
-          daily_e2ee_messages = excluded.daily_e2ee_messages,
-          daily_sent_e2ee_messages = excluded.daily_sent_e2ee_messages,
-          monthly_active_users = excluded.monthly_active_users,
-          r30_users_all = excluded.r30_users_all,
-          r30_users_android = excluded.r30_users_android,
-          r30_users_ios = excluded.r30_users_ios,
-          r30_users_electron = excluded.r30_users_electron,
-          r30_users_web = excluded.r30_users_web,
-          r30v2_users_all = excluded.r30_users_all,
-          r30v2_users_android = excluded.r30_users_android,
-          r30v2_users_ios = excluded.r30_users_ios,
-          r30v2_users_electron = excluded.r30_users_electron,
-          r30v2_users_web = excluded.r30_users_web,
-          daily_user_type_native = excluded.daily_user_type_native,
-          daily_user_type_bridged = excluded.daily_user_type_bridged,
-          daily_user_type_guest = excluded.daily_user_type_guest,
-          daily_active_homeservers = excluded.daily_active_homeservers;"#,
+          total_messages = totals.total_messages,
+          total_e2ee_messages = totals.total_e2ee_messages
+        FROM
+          totals
+        WHERE
+          t.day = totals.day
+          AND t.day >= $1"#,
         day
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await
-    .context("could not aggregate stats")?;
-
-    let _ = sqlx::query!(
+    .context("could not refresh message totals of aggregated_stats")?;
+    sqlx::query!(
         r#"
-          WITH totals AS (
-              SELECT
-                  day,
-                  server_context,
-                  SUM(daily_messages) OVER (
-                      PARTITION BY server_context
-                      ORDER BY day
-                      ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
-                  ) AS total_messages,
-                  SUM(daily_e2ee_messages) OVER (
-                      PARTITION BY server_context
-                      ORDER BY day
-                      ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
-                  ) AS total_e2ee_messages
-              FROM aggregated_stats_by_context
+        WITH
+          totals AS (
+            SELECT
+              day,
+              server_context,
+              SUM(daily_messages) OVER (
+                PARTITION BY server_context
+                ORDER BY day
+                ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+              ) AS total_messages,
+              SUM(daily_e2ee_messages) OVER (
+                PARTITION BY server_context
+                ORDER BY day
+                ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+              ) AS total_e2ee_messages
+            FROM
+              aggregated_stats_by_context
           )
-          UPDATE aggregated_stats_by_context t
-          SET
-              total_messages = totals.total_messages,
-              total_e2ee_messages = totals.total_e2ee_messages
-          FROM totals
-          WHERE t.day = totals.day AND t.server_context = totals.server_context AND t.day = $1;
-"#,
+        UPDATE aggregated_stats_by_context t
+        SET
+          total_messages = totals.total_messages,
+          total_e2ee_messages = totals.total_e2ee_messages
+        FROM
+          totals
+        WHERE
+          t.day = totals.day
+          AND t.server_context = totals.server_context
+          AND t.day >= $1"#,
         day
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await
-    .context(
-        "could not add total_messages and total_e2ee_messages to aggregated_stats_by_context",
-    )?;
-
-    info!("Aggregated stats for {day} with contexts generated successfully");
-
+    .context("could not refresh message totals of aggregated_stats_by_context")?;
+    tx.commit().await?;
     Ok(())
 }
 
-pub async fn get_aggregated_stats(
+/// Records an administrative action in the audit log, `error` telling why it
+/// failed
+pub async fn record_audit(
     db_settings: &DBSettings,
-    day: sqlx::types::time::Date,
-) -> Result<Option<AggregatedStats>> {
+    actor: &Actor,
+    action: &str,
+    parameters: &serde_json::Value,
+    error: Option<&str>,
+) -> Result<()> {
     let pool = get_db_pool(db_settings).await;
-    Ok(sqlx::query_as!(
-        AggregatedStats,
-        "SELECT * FROM aggregated_stats WHERE day = $1",
-        day
+    let outcome = if error.is_some() {
+        AuditOutcome::Failed
+    } else {
+        AuditOutcome::Succeeded
+    };
+    sqlx::query!(
+        r#"
+        INSERT INTO
+          audit_log (actor, api_key_id, action, parameters, outcome, error)
+        VALUES
+          ($1, $2, $3, $4, $5, $6)"#,
+        actor.label,
+        actor.api_key_id,
+        action,
+        parameters,
+        outcome as _,
+        error
     )
-    .fetch_optional(&pool)
-    .await?)
+    .execute(&pool)
+    .await
+    .with_context(|| {
+        format!(
+            "failed recording {action} by {} in the audit log",
+            actor.label
+        )
+    })?;
+    Ok(())
 }
 
-pub async fn get_aggregated_stats_by_context(
+/// Filters of the audit log
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    /// Entries from then on
+    pub since: Option<time::OffsetDateTime>,
+    /// Entries before then
+    pub until: Option<time::OffsetDateTime>,
+}
+
+/// Audit log entries matching `filter`, newest first, along with their total
+pub async fn get_audit_log(
     db_settings: &DBSettings,
-    day: sqlx::types::time::Date,
-    server_context: String,
-) -> Result<Option<AggregatedStatsByContext>> {
+    filter: &AuditFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AuditEntry>, i64)> {
     let pool = get_db_pool(db_settings).await;
-    Ok(sqlx::query_as!(
-        AggregatedStatsByContext,
-        "SELECT * FROM aggregated_stats_by_context WHERE day = $1 AND server_context = $2",
-        day,
-        server_context
+    let total = sqlx::query_scalar!(
+        r#"
+        SELECT
+          COUNT(*) AS "total!"
+        FROM
+          audit_log
+        WHERE
+          ($1::TEXT IS NULL OR actor = $1)
+          AND ($2::TEXT IS NULL OR action = $2)
+          AND ($3::TEXT IS NULL OR outcome = $3)
+          AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4)
+          AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5)"#,
+        filter.actor,
+        filter.action,
+        filter.outcome as _,
+        filter.since,
+        filter.until
     )
-    .fetch_optional(&pool)
-    .await?)
+    .fetch_one(&pool)
+    .await?;
+    let entries = sqlx::query_as!(
+        AuditEntry,
+        r#"
+        SELECT
+          id,
+          created_at,
+          actor,
+          api_key_id,
+          action,
+          parameters,
+          outcome AS "outcome: AuditOutcome",
+          error
+        FROM
+          audit_log
+        WHERE
+          ($1::TEXT IS NULL OR actor = $1)
+          AND ($2::TEXT IS NULL OR action = $2)
+          AND ($3::TEXT IS NULL OR outcome = $3)
+          AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4)
+          AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5)
+        ORDER BY
+          id DESC
+        LIMIT
+          $6
+        OFFSET
+          $7"#,
+        filter.actor,
+        filter.action,
+        filter.outcome as _,
+        filter.since,
+        filter.until,
+        limit,
+        offset
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok((entries, total))
 }
 
 #[allow(clippy::too_many_lines)]
-#[instrument(skip(pool, report))]
-async fn save_report(pool: &PgPool, report: &Report) -> Result<i64> {
+#[instrument(skip(conn, report))]
+async fn save_report(conn: &mut PgConnection, report: &Report) -> Result<i64> {
     #[derive(sqlx::FromRow)]
     struct Id {
         id: i64,
@@ -471,7 +2479,8 @@ async fn save_report(pool: &PgPool, report: &Report) -> Result<i64> {
             database_engine,
             database_server_version,
             server_context,
-            log_level
+            log_level,
+            excluded
           )
         VALUES
           (
@@ -514,7 +2523,8 @@ async fn save_report(pool: &PgPool, report: &Report) -> Result<i64> {
             $37,
             $38,
             $39,
-            $40
+            $40,
+            $41
           ) RETURNING id;"#,
         report.homeserver,
         report.local_timestamp,
@@ -556,8 +2566,9 @@ async fn save_report(pool: &PgPool, report: &Report) -> Result<i64> {
         report.database_server_version,
         report.server_context,
         report.log_level,
+        report.excluded,
     )
-    .fetch_one(pool)
+    .fetch_one(conn)
     .await
     .context("failed executing aggregation query.")?;
 
@@ -572,10 +2583,19 @@ async fn save_report(pool: &PgPool, report: &Report) -> Result<i64> {
 #[cfg(test)]
 pub mod tests {
     use crate::model::Report;
+    use crate::settings::AnomalySettings;
     use anyhow::Result;
 
     pub async fn save_report(pool: &sqlx::PgPool, report: &Report) -> Result<i64> {
-        super::save_report(pool, report).await
+        super::save_report(&mut *pool.acquire().await?, report).await
+    }
+
+    pub async fn store_report(
+        pool: &sqlx::PgPool,
+        report: &Report,
+        anomaly: &AnomalySettings,
+    ) -> Result<Option<i64>> {
+        super::store_report(pool, report, anomaly).await
     }
 
     pub async fn get_report_by_id(pool: &sqlx::PgPool, id: i64) -> Result<Report> {
@@ -622,7 +2642,8 @@ pub mod tests {
               database_engine,
               database_server_version,
               server_context,
-              log_level
+              log_level,
+              excluded
             FROM
               reports
             WHERE
@@ -0,0 +1,106 @@
+use std::convert::Infallible;
+
+use axum::body::{Body, Bytes};
+use axum::response::{IntoResponse, Response};
+use http::{HeaderMap, HeaderValue, header};
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+/// Response formats of the aggregated stats endpoints
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// Picks the format from the `format` query parameter if given, otherwise
+    /// from the `Accept` header. JSON is used unless CSV is preferred.
+    pub fn negotiate(param: Option<Self>, headers: &HeaderMap) -> Self {
+        if let Some(format) = param {
+            return format;
+        }
+
+        let mut csv = 0.0;
+        let mut json = 0.0;
+        for range in headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media_type.as_str() {
+                "text/csv" => csv = quality,
+                "application/json" => json = quality,
+                _ => {}
+            }
+        }
+
+        if csv > 0.0 && csv > json {
+            Self::Csv
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Streams `rows` as CSV, one line per row with the values of `columns` in
+/// that order, after a header line
+pub fn csv_response(
+    columns: Vec<&'static str>,
+    rows: Vec<serde_json::Map<String, serde_json::Value>>,
+) -> Response {
+    let header = csv_line(columns.iter().copied());
+    let lines = rows.into_iter().map(move |row| {
+        csv_line(columns.iter().map(|column| match row.get(*column) {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(value)) => defuse_formula(value),
+            // Nested values, like the coverage counts, are kept as JSON
+            Some(value) => value.to_string(),
+        }))
+    });
+    let body = futures_util::stream::iter(
+        std::iter::once(header)
+            .chain(lines)
+            .map(Ok::<_, Infallible>),
+    );
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/csv; charset=utf-8"),
+        )],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+/// Prefixes text which spreadsheets would evaluate as a formula with `'`, as
+/// strings like the server context come from the anonymous push endpoint
+fn defuse_formula(value: &str) -> String {
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Encodes a single CSV record, including the line terminator
+fn csv_line<I>(fields: I) -> Bytes
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    // Writing into a `Vec` can't fail
+    writer.write_record(fields).expect("CSV record");
+    Bytes::from(writer.into_inner().expect("CSV buffer"))
+}
@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, ensure};
+use config::{Config, File};
+use glob::{MatchOptions, Pattern};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::auth::Actor;
+use crate::metrics;
+use crate::model::{IngestAction, IngestRule, IngestRuleHits, Report};
+use crate::settings::{DBSettings, IngestRulesSettings};
+
+/// Contents of the ingest rules file
+#[derive(Debug, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<IngestRule>,
+}
+
+/// Homeserver names and server contexts are matched case-insensitively, and
+/// `*` also matches dots
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// An ingest rule with its conditions parsed
+#[derive(Debug)]
+struct Rule {
+    rule: IngestRule,
+    homeserver: Option<Pattern>,
+    network: Option<IpNet>,
+    server_context: Option<Pattern>,
+}
+
+impl Rule {
+    fn compile(rule: IngestRule) -> Result<Self> {
+        let pattern = |pattern: Option<&str>, field: &str| {
+            pattern
+                .map(Pattern::new)
+                .transpose()
+                .with_context(|| format!("invalid {field} pattern in rule {:?}", rule.name))
+        };
+        let homeserver = pattern(rule.homeserver.as_deref(), "homeserver")?;
+        let server_context = pattern(rule.server_context.as_deref(), "server_context")?;
+        // A single address is a network of its own
+        let network = rule
+            .network
+            .as_deref()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+            })
+            .transpose()
+            .with_context(|| format!("invalid network in rule {:?}", rule.name))?;
+        ensure!(
+            homeserver.is_some() || network.is_some() || server_context.is_some(),
+            "rule {:?} has no condition",
+            rule.name
+        );
+
+        Ok(Self {
+            rule,
+            homeserver,
+            network,
+            server_context,
+        })
+    }
+
+    fn matches(&self, report: &Report, addr: IpAddr) -> bool {
+        let matches = |pattern: Option<&Pattern>, value: Option<&str>| {
+            pattern.is_none_or(|pattern| {
+                value.is_some_and(|value| pattern.matches_with(value, MATCH_OPTIONS))
+            })
+        };
+        matches(self.homeserver.as_ref(), report.homeserver.as_deref())
+            && matches(
+                self.server_context.as_ref(),
+                report.server_context.as_deref(),
+            )
+            && self
+                .network
+                .is_none_or(|network| network.contains(&addr.to_canonical()))
+    }
+
+    fn hits(&self) -> u64 {
+        metrics::INGEST_RULE_HITS
+            .with_label_values(&[self.rule.name.as_str(), self.rule.action.as_str()])
+            .get()
+    }
+}
+
+/// The ingest rules in effect, replaced as a whole when the rules file changes
+#[derive(Debug, Default)]
+pub struct IngestRules {
+    rules: RwLock<Arc<[Rule]>>,
+}
+
+impl IngestRules {
+    /// Loads the rules from `path`, in any format supported for the config
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            rules: RwLock::new(parse(path)?.into()),
+        })
+    }
+
+    fn current(&self) -> Arc<[Rule]> {
+        self.rules
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The action of the first rule matching a report sent from `addr`, if
+    /// any, counting the hit
+    pub fn evaluate(&self, report: &Report, addr: IpAddr) -> Option<IngestAction> {
+        let rules = self.current();
+        let rule = rules.iter().find(|rule| rule.matches(report, addr))?;
+        debug!(
+            "Report of {:?} from {addr} matched ingest rule {:?}",
+            report.homeserver, rule.rule.name
+        );
+        metrics::INGEST_RULE_HITS
+            .with_label_values(&[rule.rule.name.as_str(), rule.rule.action.as_str()])
+            .inc();
+        Some(rule.rule.action)
+    }
+
+    /// The rules in order, along with their hit counts
+    pub fn hits(&self) -> Vec<IngestRuleHits> {
+        self.current()
+            .iter()
+            .map(|rule| IngestRuleHits {
+                rule: rule.rule.clone(),
+                hits: rule.hits(),
+            })
+            .collect()
+    }
+
+    /// Reloads the rules if the file changed since `seen`, keeping the current
+    /// ones if the new file is invalid. Returns whether they were reloaded.
+    pub fn reload_if_changed(&self, path: &Path, seen: &mut Option<SystemTime>) -> Result<bool> {
+        let modified = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("can't stat {}", path.display()))?;
+        if *seen == Some(modified) {
+            return Ok(false);
+        }
+
+        let rules = parse(path).with_context(|| {
+            format!(
+                "failed reloading ingest rules {}, keeping the current ones",
+                path.display()
+            )
+        })?;
+        *self.rules.write().unwrap_or_else(PoisonError::into_inner) = rules.into();
+        *seen = Some(modified);
+        Ok(true)
+    }
+}
+
+fn parse(path: &Path) -> Result<Vec<Rule>> {
+    let file: RulesFile = Config::builder()
+        .add_source(File::from(path))
+        .build()
+        .and_then(Config::try_deserialize)
+        .with_context(|| format!("can't load ingest rules {}", path.display()))?;
+
+    let mut names = HashSet::new();
+    file.rules
+        .into_iter()
+        .map(|rule| {
+            ensure!(!rule.name.is_empty(), "ingest rules need a name");
+            ensure!(
+                names.insert(rule.name.clone()),
+                "duplicate ingest rule {:?}",
+                rule.name
+            );
+            Rule::compile(rule)
+        })
+        .collect()
+}
+
+/// Checks the rules file for changes every `reload_seconds`, recording the
+/// reloads in the audit log. A file failing the same way is only recorded once.
+pub async fn watch(
+    rules: Arc<IngestRules>,
+    settings: IngestRulesSettings,
+    db_settings: Arc<DBSettings>,
+) {
+    let mut seen = std::fs::metadata(&settings.path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let mut last_error = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.reload_seconds.max(1)));
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let error = match rules.reload_if_changed(&settings.path, &mut seen) {
+            Ok(true) => {
+                info!("Reloaded ingest rules {}", settings.path.display());
+                None
+            }
+            Ok(false) => continue,
+            Err(err) => {
+                warn!("{err:?}");
+                let error = format!("{err:#}");
+                if last_error.as_ref() == Some(&error) {
+                    continue;
+                }
+                Some(error)
+            }
+        };
+
+        let names = rules
+            .current()
+            .iter()
+            .map(|rule| rule.rule.name.clone())
+            .collect::<Vec<_>>();
+        let parameters = json!({ "path": settings.path, "rules": names });
+        if let Err(err) = crate::database::record_audit(
+            &db_settings,
+            &Actor::system(),
+            "ingest_rules.reload",
+            &parameters,
+            error.as_deref(),
+        )
+        .await
+        {
+            warn!("{err:?}");
+        }
+        last_error = error;
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use sqlx::{Connection, PgConnection};
+
+/// Whether this replica currently holds the leader lock
+static IS_LEADER: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether this replica is currently running the scheduled jobs
+pub fn is_leader() -> bool {
+    IS_LEADER.load(Ordering::Relaxed)
+}
+
+/// Leader election based on a session level PostgreSQL advisory lock.
+///
+/// The lock is held by a dedicated connection, so it is released by the
+/// database as soon as that connection is lost, allowing a standby replica to
+/// take over.
+#[derive(Debug)]
+pub struct LeaderLock {
+    /// `None` if leader election is disabled
+    url: Option<String>,
+    key: i64,
+    conn: Option<PgConnection>,
+}
+
+impl LeaderLock {
+    pub fn new(url: &str, key: i64) -> Self {
+        Self {
+            url: Some(url.to_owned()),
+            key,
+            conn: None,
+        }
+    }
+
+    /// A lock which is always held, for deployments with a single replica
+    pub fn disabled() -> Self {
+        IS_LEADER.store(true, Ordering::Relaxed);
+        Self {
+            url: None,
+            key: 0,
+            conn: None,
+        }
+    }
+
+    /// Checks whether the lock is still held, trying to acquire it otherwise.
+    pub async fn ensure(&mut self) -> bool {
+        let Some(url) = self.url.clone() else {
+            return true;
+        };
+
+        let was_leader = is_leader();
+        let leader = match self.try_ensure(&url).await {
+            Ok(leader) => leader,
+            Err(err) => {
+                warn!("{err:?}");
+                self.conn = None;
+                false
+            }
+        };
+        IS_LEADER.store(leader, Ordering::Relaxed);
+
+        if leader && !was_leader {
+            info!("Acquired leader lock, running scheduled jobs on this replica");
+        } else if !leader && was_leader {
+            warn!("Lost leader lock, this replica is on standby now");
+        }
+
+        leader
+    }
+
+    async fn try_ensure(&mut self, url: &str) -> Result<bool> {
+        if let Some(conn) = self.conn.as_mut() {
+            // The session lock lives as long as the connection does
+            conn.ping().await.context("leader lock connection lost")?;
+            return Ok(true);
+        }
+
+        let mut conn = PgConnection::connect(url)
+            .await
+            .context("failed connecting to PostgreSQL server for leader election.")?;
+        let acquired = sqlx::query_scalar!("SELECT pg_try_advisory_lock($1)", self.key)
+            .fetch_one(&mut conn)
+            .await
+            .context("failed trying to acquire leader lock")?
+            .unwrap_or(false);
+
+        if acquired {
+            self.conn = Some(conn);
+        } else {
+            conn.close().await.ok();
+        }
+
+        Ok(acquired)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use anyhow::{Context, Result};
+    use sqlx::Connection;
+
+    use super::{IS_LEADER, LeaderLock, Ordering};
+
+    /// Gives up the lock, so that another replica can take over
+    pub async fn release(lock: &mut LeaderLock) -> Result<()> {
+        if let Some(mut conn) = lock.conn.take() {
+            sqlx::query_scalar!("SELECT pg_advisory_unlock($1)", lock.key)
+                .fetch_one(&mut conn)
+                .await
+                .context("failed releasing leader lock")?;
+            conn.close().await?;
+            IS_LEADER.store(false, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
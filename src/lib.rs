@@ -5,42 +5,56 @@ use rust_telemetry::init_otel;
 use settings::Settings;
 use std::sync::Arc;
 
+mod auth;
+mod cli;
 mod database;
+mod export;
+mod ingest;
+mod leader;
+mod metrics;
 mod model;
 mod server;
 mod settings;
 #[cfg(test)]
 mod tests;
+mod tls;
 
 pub async fn run(opts: ArgMatches) -> Result<()> {
     let settings = Settings::load(opts.get_one::<String>("config").expect("Config string"))
         .context("can't load config.")?;
-    let _guard = init_otel!(&settings.telemetry.unwrap_or_default()).unwrap();
+    if let Some(("keys", opts)) = opts.subcommand() {
+        return cli::run_keys(&settings.database, opts).await;
+    }
+    let _guard =
+        init_otel!(&settings.telemetry.unwrap_or_default()).expect("Initializing telemetry");
 
     let (tx, rx) = tokio::sync::mpsc::channel::<model::Report>(64);
 
     let server = {
         let db_settings = Arc::new(settings.database.clone());
+        let aggregation = Arc::new(settings.aggregation.clone());
         let settings = settings.server;
         tokio::spawn(async move {
             let tx = tx.clone();
-            server::run_server(settings, db_settings, tx)
+            server::run_server(settings, db_settings, aggregation, tx)
                 .await
                 .expect("Running server");
         })
     };
 
     {
+        let aggregation = settings.aggregation.clone();
         let settings = settings.database.clone();
         tokio::spawn(async move {
-            database::aggregate_loop(&settings).await;
+            database::aggregate_loop(&settings, &aggregation).await;
         });
     }
 
     {
+        let anomaly = settings.aggregation.anomaly;
         let settings = settings.database;
         tokio::spawn(async move {
-            database::insert_reports_loop(&settings, rx).await;
+            database::insert_reports_loop(&settings, &anomaly, rx).await;
         });
     }
 
@@ -1,15 +1,69 @@
 use anyhow::Result;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command, value_parser};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let id = Arg::new("id")
+        .help("ID of the API key")
+        .required(true)
+        .value_parser(value_parser!(i64));
     let opts = Command::new("barad-dur")
         .version(env!("CARGO_PKG_VERSION"))
         .args(&[Arg::new("config")
             .help("path of config file")
             .short('c')
             .long("config")
+            .global(true)
             .default_value("./config.yaml")])
+        .subcommand(
+            Command::new("keys")
+                .about("Manage the API keys of the read endpoints")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("List all API keys"))
+                .subcommand(
+                    Command::new("create")
+                        .about("Create an API key and print its secret")
+                        .args(&[
+                            Arg::new("label")
+                                .help("what the key is used for")
+                                .long("label")
+                                .required(true),
+                            Arg::new("scope")
+                                .help("`stats:global`, `stats:context:<name>` or `admin`")
+                                .long("scope")
+                                .required(true)
+                                .action(ArgAction::Append),
+                            Arg::new("expires-at")
+                                .help("RFC 3339 time from which on the key is rejected")
+                                .long("expires-at"),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("rotate")
+                        .about("Replace the secret of an API key and print the new one")
+                        .arg(id.clone()),
+                )
+                .subcommand(
+                    Command::new("expire")
+                        .about("Set the expiry of an API key, now by default")
+                        .args(&[
+                            id.clone(),
+                            Arg::new("at")
+                                .help("RFC 3339 time from which on the key is rejected")
+                                .long("at"),
+                            Arg::new("never")
+                                .help("remove the expiry")
+                                .long("never")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("at"),
+                        ]),
+                )
+                .subcommand(
+                    Command::new("revoke")
+                        .about("Revoke an API key for good")
+                        .arg(id),
+                ),
+        )
         .get_matches();
 
     barad_dur::run(opts).await?;
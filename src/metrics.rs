@@ -0,0 +1,190 @@
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+use crate::model::{AggregatedStats, AggregatedStatsByContext};
+
+/// Prefix of all exported metrics
+const NAMESPACE: &str = "barad_dur";
+
+/// Registry of the service metrics, the fleet gauges are built per scrape
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| {
+    Registry::new_custom(Some(NAMESPACE.to_owned()), None).expect("metrics registry")
+});
+
+/// Registers `metric` with [`REGISTRY`]
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("registering metric");
+    metric
+}
+
+pub static REPORTS_RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new("reports_received_total", "Reports accepted for storage").expect("metric"),
+    )
+});
+
+pub static REPORTS_REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("reports_rejected_total", "Reports which were not stored"),
+            &["reason"],
+        )
+        .expect("metric"),
+    )
+});
+
+pub static REPORTS_FLAGGED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "reports_flagged_total",
+            "Reports held back for review by anomaly detection",
+        )
+        .expect("metric"),
+    )
+});
+
+pub static INGEST_RULE_HITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "ingest_rule_hits_total",
+                "Reports matched by an ingest rule",
+            ),
+            &["rule", "action"],
+        )
+        .expect("metric"),
+    )
+});
+
+pub static CHANNEL_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "report_channel_depth",
+            "Reports waiting to be written to the database",
+        )
+        .expect("metric"),
+    )
+});
+
+pub static INSERT_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "report_insert_duration_seconds",
+            "Time taken to write a report to the database",
+        ))
+        .expect("metric"),
+    )
+});
+
+pub static AGGREGATION_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "aggregation_duration_seconds",
+                "Time taken by aggregation runs",
+            )
+            .buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0]),
+            &["scope", "status"],
+        )
+        .expect("metric"),
+    )
+});
+
+pub static AGGREGATION_LAST_SUCCESS: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "aggregation_last_success_timestamp_seconds",
+                "Unix time of the last successful aggregation run",
+            ),
+            &["scope"],
+        )
+        .expect("metric"),
+    )
+});
+
+pub static LEADER: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(
+        IntGauge::new(
+            "leader",
+            "Whether this replica is running the scheduled jobs",
+        )
+        .expect("metric"),
+    )
+});
+
+/// Aggregated stats of the latest aggregated day
+#[derive(Debug, Clone, Default)]
+pub struct Fleet {
+    pub global: Option<AggregatedStats>,
+    pub contexts: Vec<AggregatedStatsByContext>,
+}
+
+/// Renders the service metrics, and the fleet gauges if given, in the
+/// Prometheus text format.
+///
+/// Every numeric field of the aggregated stats becomes a gauge named
+/// `barad_dur_aggregated_<field>`, labelled with the `server_context`, which
+/// is empty for the global stats.
+pub fn render(fleet: Option<Fleet>) -> Result<String> {
+    // Make sure all service metrics are registered, even if never touched
+    LazyLock::force(&REPORTS_RECEIVED);
+    LazyLock::force(&REPORTS_REJECTED);
+    LazyLock::force(&INGEST_RULE_HITS);
+    LazyLock::force(&REPORTS_FLAGGED);
+    LazyLock::force(&CHANNEL_DEPTH);
+    LazyLock::force(&INSERT_LATENCY);
+    LazyLock::force(&AGGREGATION_DURATION);
+    LazyLock::force(&AGGREGATION_LAST_SUCCESS);
+    LEADER.set(i64::from(crate::leader::is_leader()));
+
+    let mut families = REGISTRY.gather();
+
+    if let Some(fleet) = fleet {
+        let registry = Registry::new_custom(Some(format!("{NAMESPACE}_aggregated")), None)?;
+        let mut gauges = std::collections::BTreeMap::new();
+        let stats = fleet
+            .global
+            .map(serde_json::to_value)
+            .into_iter()
+            .chain(fleet.contexts.into_iter().map(serde_json::to_value))
+            .collect::<Result<Vec<_>, _>>()?;
+        for stats in &stats {
+            let serde_json::Value::Object(stats) = stats else {
+                continue;
+            };
+            let context = stats
+                .get("server_context")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default();
+            for (field, value) in stats {
+                let Some(value) = value.as_f64() else {
+                    continue;
+                };
+                if !gauges.contains_key(field) {
+                    let gauge = GaugeVec::new(
+                        Opts::new(field.clone(), format!("Latest aggregated {field}")),
+                        &["server_context"],
+                    )?;
+                    registry.register(Box::new(gauge.clone()))?;
+                    gauges.insert(field.clone(), gauge);
+                }
+                gauges[field].with_label_values(&[context]).set(value);
+            }
+        }
+        families.extend(registry.gather());
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&families, &mut buffer)
+        .context("failed encoding metrics")?;
+    String::from_utf8(buffer).context("metrics are not valid UTF-8")
+}
@@ -1,8 +1,139 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+/// Numeric report fields which get a representative daily value per
+/// homeserver in `homeserver_daily`
+pub const DAILY_METRICS: &[&str] = &[
+    "uptime_seconds",
+    "total_users",
+    "total_nonbridged_users",
+    "total_room_count",
+    "daily_active_users",
+    "daily_active_rooms",
+    "daily_messages",
+    "daily_sent_messages",
+    "daily_active_e2ee_rooms",
+    "daily_e2ee_messages",
+    "daily_sent_e2ee_messages",
+    "monthly_active_users",
+    "r30_users_all",
+    "r30_users_android",
+    "r30_users_ios",
+    "r30_users_electron",
+    "r30_users_web",
+    "r30v2_users_all",
+    "r30v2_users_android",
+    "r30v2_users_ios",
+    "r30v2_users_electron",
+    "r30v2_users_web",
+    "cpu_average",
+    "memory_rss",
+    "cache_factor",
+    "event_cache_size",
+    "daily_user_type_native",
+    "daily_user_type_bridged",
+    "daily_user_type_guest",
+];
+
+/// Metrics which are summed up over all homeservers in `aggregated_stats` and
+/// `aggregated_stats_by_context`
+pub const AGGREGATED_METRICS: &[&str] = &[
+    "total_users",
+    "total_nonbridged_users",
+    "total_room_count",
+    "daily_active_users",
+    "daily_active_rooms",
+    "daily_messages",
+    "daily_sent_messages",
+    "daily_active_e2ee_rooms",
+    "daily_e2ee_messages",
+    "daily_sent_e2ee_messages",
+    "monthly_active_users",
+    "r30_users_all",
+    "r30_users_android",
+    "r30_users_ios",
+    "r30_users_electron",
+    "r30_users_web",
+    "r30v2_users_all",
+    "r30v2_users_android",
+    "r30v2_users_ios",
+    "r30v2_users_electron",
+    "r30v2_users_web",
+    "daily_user_type_native",
+    "daily_user_type_bridged",
+    "daily_user_type_guest",
+];
+
+/// How the daily value of a homeserver is chosen if it sent several reports
+/// on the same day
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    /// The value of the latest report
+    #[default]
+    Latest,
+    /// The maximum over all reports
+    Max,
+    /// The mean over all reports
+    Mean,
+    /// The value of the first report at or after the cutoff time, falling back
+    /// to the latest report if there is none
+    FirstAfterCutoff,
+}
+
+/// Selection strategy per metric, recorded with every aggregated row
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Default)]
+pub struct DailyStrategy {
+    /// Strategy for all metrics not listed in `metrics`
+    #[serde(default)]
+    pub default: SelectionStrategy,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metrics: BTreeMap<String, SelectionStrategy>,
+    /// Time of day as `HH:MM`, used by `first_after_cutoff`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cutoff: Option<String>,
+}
+
+impl DailyStrategy {
+    pub fn for_metric(&self, metric: &str) -> SelectionStrategy {
+        self.metrics.get(metric).copied().unwrap_or(self.default)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(metric) = self
+            .metrics
+            .keys()
+            .find(|metric| !DAILY_METRICS.contains(&metric.as_str()))
+        {
+            bail!("unknown metric {metric:?} in daily strategy");
+        }
+
+        let needs_cutoff = self.default == SelectionStrategy::FirstAfterCutoff
+            || self
+                .metrics
+                .values()
+                .any(|strategy| *strategy == SelectionStrategy::FirstAfterCutoff);
+        match &self.cutoff {
+            Some(cutoff) => {
+                let format = time::format_description::parse("[hour]:[minute]")?;
+                time::Time::parse(cutoff, &format)
+                    .with_context(|| format!("invalid cutoff {cutoff:?}, expected HH:MM"))?;
+            }
+            None if needs_cutoff => bail!("daily strategy first_after_cutoff requires a cutoff"),
+            None => {}
+        }
 
-#[derive(Debug, Deserialize, PartialEq, FromRow, Clone)]
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, FromRow, Clone, ToSchema)]
 pub struct Report {
     pub homeserver: Option<String>,
     #[serde(with = "time::serde::timestamp::option", default)]
@@ -50,9 +181,12 @@ pub struct Report {
     pub database_server_version: Option<String>,
     pub server_context: Option<String>,
     pub log_level: Option<String>,
+    /// Set by an `exclude` ingest rule, never sent by homeservers
+    #[serde(skip)]
+    pub excluded: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
 pub struct AggregatedStats {
     pub day: sqlx::types::time::Date,
     pub total_users: Option<i64>,
@@ -82,9 +216,15 @@ pub struct AggregatedStats {
     pub daily_user_type_bridged: Option<i64>,
     pub daily_user_type_guest: Option<i64>,
     pub daily_active_homeservers: Option<i64>,
+    pub daily_strategy: Option<serde_json::Value>,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub computed_at: Option<OffsetDateTime>,
+    pub report_count: Option<i64>,
+    /// Number of homeservers which contributed a value, per metric
+    pub coverage: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
 pub struct AggregatedStatsByContext {
     pub day: sqlx::types::time::Date,
     pub server_context: String,
@@ -115,4 +255,594 @@ pub struct AggregatedStatsByContext {
     pub daily_user_type_bridged: Option<i64>,
     pub daily_user_type_guest: Option<i64>,
     pub daily_active_homeservers: Option<i64>,
+    pub daily_strategy: Option<serde_json::Value>,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub computed_at: Option<OffsetDateTime>,
+    pub report_count: Option<i64>,
+    /// Number of homeservers which contributed a value, per metric
+    pub coverage: Option<serde_json::Value>,
+}
+
+/// Aggregated stats whose summed metrics can be scaled up to the full
+/// population of homeservers
+pub trait Extrapolate {
+    /// Sets every summed metric to `sum * daily_active_homeservers / coverage`,
+    /// so that growth in the number of homeservers sending a metric doesn't
+    /// look like growth of the metric itself
+    fn extrapolate(&mut self);
+}
+
+/// Scales `sum` from the `covered` homeservers up to all `homeservers`, or
+/// gives `None` if that doesn't fit
+fn extrapolated(sum: i64, homeservers: i64, covered: i64) -> Option<i64> {
+    if covered <= 0 {
+        return Some(sum);
+    }
+    let scaled =
+        (i128::from(sum) * i128::from(homeservers) + i128::from(covered) / 2) / i128::from(covered);
+    i64::try_from(scaled).ok()
+}
+
+/// Implements [`Extrapolate`] for stats with the [`AGGREGATED_METRICS`] as
+/// fields
+macro_rules! impl_extrapolate {
+    ($stats:ty) => {
+        impl Extrapolate for $stats {
+            fn extrapolate(&mut self) {
+                impl_extrapolate!(
+                    self;
+                    total_users,
+                    total_nonbridged_users,
+                    total_room_count,
+                    daily_active_users,
+                    daily_active_rooms,
+                    daily_messages,
+                    daily_sent_messages,
+                    daily_active_e2ee_rooms,
+                    daily_e2ee_messages,
+                    daily_sent_e2ee_messages,
+                    monthly_active_users,
+                    r30_users_all,
+                    r30_users_android,
+                    r30_users_ios,
+                    r30_users_electron,
+                    r30_users_web,
+                    r30v2_users_all,
+                    r30v2_users_android,
+                    r30v2_users_ios,
+                    r30v2_users_electron,
+                    r30v2_users_web,
+                    daily_user_type_native,
+                    daily_user_type_bridged,
+                    daily_user_type_guest,
+                );
+            }
+        }
+    };
+    ($stats:ident; $($metric:ident),+ $(,)?) => {
+        let (Some(homeservers), Some(coverage)) =
+            ($stats.daily_active_homeservers, &$stats.coverage)
+        else {
+            return;
+        };
+        $(
+            if let (Some(sum), Some(covered)) =
+                ($stats.$metric, coverage[stringify!($metric)].as_i64())
+            {
+                $stats.$metric = extrapolated(sum, homeservers, covered);
+            }
+        )+
+    };
+}
+
+impl_extrapolate!(AggregatedStats);
+impl_extrapolate!(AggregatedStatsByContext);
+
+/// Aggregated stats for every day of a date range
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, ToSchema)]
+pub struct StatsRange {
+    pub from: sqlx::types::time::Date,
+    pub to: sqlx::types::time::Date,
+    /// One entry per day with stats, ordered by day
+    pub stats: Vec<serde_json::Value>,
+    /// Days of the range without any stats
+    pub missing_days: Vec<sqlx::types::time::Date>,
+}
+
+/// A server context seen in the aggregated stats
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct ContextSummary {
+    pub server_context: String,
+    pub first_day: sqlx::types::time::Date,
+    pub last_day: sqlx::types::time::Date,
+    /// Active homeservers on the last day
+    pub homeservers: Option<i64>,
+    /// Total users on the last day
+    pub total_users: Option<i64>,
+}
+
+/// An API key, without the key itself
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, FromRow, ToSchema)]
+pub struct ApiKey {
+    pub id: i64,
+    /// What the key is used for
+    pub label: String,
+    /// `stats:global`, `stats:context:<name>` or `admin`
+    pub scopes: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// The key is rejected from then on
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub expires_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub revoked_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// A new API key
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct NewApiKey {
+    pub label: String,
+    pub scopes: Vec<String>,
+    #[serde(with = "time::serde::rfc3339::option", default)]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+/// An API key along with its secret, which is only ever shown once
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct ApiKeySecret {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    /// The bearer token
+    pub secret: String,
+}
+
+/// New expiry of an API key, `null` for none
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct ApiKeyExpiry {
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+/// What happens to a pushed report matching an ingest rule
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestAction {
+    /// Store the report as usual, skipping the rules after this one
+    Allow,
+    /// Acknowledge the report without storing it
+    Drop,
+    /// Store the report, but leave it out of the aggregation
+    Exclude,
+    /// Refuse the report with `403 Forbidden`
+    Reject,
+}
+
+impl IngestAction {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Drop => "drop",
+            Self::Exclude => "exclude",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+/// A rule of the ingest rules file. All conditions given must match.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct IngestRule {
+    /// Unique name, used as label of the hit counter
+    pub name: String,
+    pub action: IngestAction,
+    /// Glob pattern of the homeserver name, e.g. `*.ci.example.com`
+    #[serde(default)]
+    pub homeserver: Option<String>,
+    /// Network of the client address in CIDR notation, e.g. `10.0.0.0/8`
+    #[serde(default)]
+    pub network: Option<String>,
+    /// Glob pattern of the server context
+    #[serde(default)]
+    pub server_context: Option<String>,
+}
+
+/// An ingest rule along with the number of reports it matched
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct IngestRuleHits {
+    #[serde(flatten)]
+    pub rule: IngestRule,
+    /// Reports matched by this replica since it started
+    pub hits: u64,
+}
+
+/// Review of a report flagged by anomaly detection
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum ReviewStatus {
+    /// Left out of the aggregation until reviewed
+    Flagged,
+    /// Aggregated like any other report
+    Confirmed,
+    /// Left out of the aggregation for good
+    Rejected,
+}
+
+/// A report scored as anomalous against the history of its homeserver
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
+pub struct FlaggedReport {
+    pub id: i64,
+    pub homeserver: Option<String>,
+    pub server_context: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub local_timestamp: Option<OffsetDateTime>,
+    /// Highest ratio of a metric to its median over the history
+    pub anomaly_score: Option<f64>,
+    /// Ratios of the metrics above their threshold
+    pub anomalies: Option<serde_json::Value>,
+    pub review_status: ReviewStatus,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub reviewed_at: Option<OffsetDateTime>,
+}
+
+/// How an administrative action turned out
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Succeeded,
+    Failed,
+}
+
+/// An administrative action recorded in the audit log
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// Label of the API key, `admin_token` for the configured admin token,
+    /// `cli:<user>` for the command line and `system` for the server itself
+    pub actor: String,
+    /// ID of the API key, if the action was taken with one
+    pub api_key_id: Option<i64>,
+    /// What was done, e.g. `api_key.revoke`
+    pub action: String,
+    /// Parameters of the action, never including secrets
+    pub parameters: serde_json::Value,
+    pub outcome: AuditOutcome,
+    /// Why the action failed
+    pub error: Option<String>,
+}
+
+/// What to erase of a homeserver, all of its reports by default
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Default, ToSchema)]
+pub struct ErasureRequest {
+    /// First day to erase, as `YYYY-MM-DD`
+    #[serde(default)]
+    pub from: Option<sqlx::types::time::Date>,
+    /// Last day to erase, as `YYYY-MM-DD`
+    #[serde(default)]
+    pub to: Option<sqlx::types::time::Date>,
+    /// Refuse the reports of the homeserver from then on
+    #[serde(default)]
+    pub refuse_reports: bool,
+}
+
+/// What was erased of a homeserver
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct Erasure {
+    pub homeserver: String,
+    pub from: Option<sqlx::types::time::Date>,
+    pub to: Option<sqlx::types::time::Date>,
+    pub reports_deleted: i64,
+    /// Days of the deleted reports, which are aggregated again
+    pub days: Vec<sqlx::types::time::Date>,
+    /// Days whose aggregation failed, which the scheduled jobs catch up on
+    pub pending_days: Vec<sqlx::types::time::Date>,
+    /// Server contexts of the deleted reports
+    pub server_contexts: Vec<String>,
+    /// Whether the reports of the homeserver are refused from then on
+    pub refuse_reports: bool,
+}
+
+/// Lifecycle status of a homeserver, by the time since its last report
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum HomeserverStatus {
+    /// Reported within `aggregation.stale_days`
+    Active,
+    /// Reported within `aggregation.churn_days`
+    Stale,
+    Churned,
+}
+
+/// A homeserver as seen in its reports
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, Clone, ToSchema)]
+pub struct HomeserverSummary {
+    pub homeserver: String,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub first_report: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_report: Option<OffsetDateTime>,
+    pub report_count: i64,
+    /// Synapse version from the user agent of the last report
+    pub version: Option<String>,
+    pub user_agent: Option<String>,
+    pub server_context: Option<String>,
+    pub total_users: Option<i64>,
+    pub total_nonbridged_users: Option<i64>,
+    pub daily_active_users: Option<i64>,
+    pub monthly_active_users: Option<i64>,
+    pub status: HomeserverStatus,
+}
+
+/// Daily representative values of one homeserver, see `homeserver_daily`.
+/// Days which weren't aggregated yet only have a `report_count`.
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
+pub struct HomeserverDay {
+    pub day: sqlx::types::time::Date,
+    /// Number of reports received on that day
+    pub report_count: i64,
+    /// Report the latest fields were taken from
+    pub report_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub local_timestamp: Option<OffsetDateTime>,
+    pub uptime_seconds: Option<i64>,
+    pub total_users: Option<i64>,
+    pub total_nonbridged_users: Option<i64>,
+    pub total_room_count: Option<i64>,
+    pub daily_active_users: Option<i64>,
+    pub daily_active_rooms: Option<i64>,
+    pub daily_messages: Option<i64>,
+    pub daily_sent_messages: Option<i64>,
+    pub daily_active_e2ee_rooms: Option<i64>,
+    pub daily_e2ee_messages: Option<i64>,
+    pub daily_sent_e2ee_messages: Option<i64>,
+    pub monthly_active_users: Option<i64>,
+    pub r30_users_all: Option<i64>,
+    pub r30_users_android: Option<i64>,
+    pub r30_users_ios: Option<i64>,
+    pub r30_users_electron: Option<i64>,
+    pub r30_users_web: Option<i64>,
+    pub r30v2_users_all: Option<i64>,
+    pub r30v2_users_android: Option<i64>,
+    pub r30v2_users_ios: Option<i64>,
+    pub r30v2_users_electron: Option<i64>,
+    pub r30v2_users_web: Option<i64>,
+    pub cpu_average: Option<i64>,
+    pub memory_rss: Option<i64>,
+    pub cache_factor: Option<f64>,
+    pub event_cache_size: Option<i64>,
+    pub user_agent: Option<String>,
+    pub daily_user_type_native: Option<i64>,
+    pub daily_user_type_bridged: Option<i64>,
+    pub daily_user_type_guest: Option<i64>,
+    pub python_version: Option<String>,
+    pub database_engine: Option<String>,
+    pub database_server_version: Option<String>,
+    pub server_context: Option<String>,
+    pub log_level: Option<String>,
+    pub daily_strategy: Option<serde_json::Value>,
+}
+
+/// New, returning and churned homeservers of a day. A homeserver churns on
+/// the day it has gone `churn_days` days without reports.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, Clone, ToSchema)]
+pub struct HomeserverChurn {
+    pub day: sqlx::types::time::Date,
+    pub active_homeservers: i64,
+    /// Homeservers sending their first report ever
+    pub new_homeservers: i64,
+    /// Homeservers reporting again after having churned
+    pub returning_homeservers: i64,
+    pub churned_homeservers: i64,
+    pub churn_days: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub computed_at: OffsetDateTime,
+}
+
+/// [`HomeserverChurn`] within a server context
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, FromRow, Clone, ToSchema)]
+pub struct HomeserverChurnByContext {
+    pub day: sqlx::types::time::Date,
+    pub server_context: String,
+    pub active_homeservers: i64,
+    pub new_homeservers: i64,
+    pub returning_homeservers: i64,
+    pub churned_homeservers: i64,
+    pub churn_days: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub computed_at: OffsetDateTime,
+}
+
+/// Aggregate functions over the daily values of homeservers
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateFunction {
+    #[default]
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggregateFunction {
+    pub const fn sql(self) -> &'static str {
+        match self {
+            Self::Sum => "SUM",
+            Self::Avg => "AVG",
+            Self::Min => "MIN",
+            Self::Max => "MAX",
+            Self::Count => "COUNT",
+        }
+    }
+}
+
+/// Dimensions of `homeserver_daily` which `/admin/query` can group by
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimension {
+    ServerContext,
+    /// Synapse version parsed from the user agent
+    SynapseVersion,
+    DatabaseEngine,
+    DatabaseServerVersion,
+    PythonVersion,
+    LogLevel,
+}
+
+impl Dimension {
+    /// SQL expression of the dimension
+    pub const fn sql(self) -> &'static str {
+        match self {
+            Self::ServerContext => "server_context",
+            Self::SynapseVersion => "SUBSTRING(user_agent FROM 'Synapse/([^ ]+)')",
+            Self::DatabaseEngine => "database_engine",
+            Self::DatabaseServerVersion => "database_server_version",
+            Self::PythonVersion => "python_version",
+            Self::LogLevel => "log_level",
+        }
+    }
+}
+
+/// One group of a [`QueryResult`]
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
+pub struct QueryGroup {
+    /// Value of the dimension, `None` for homeservers without one
+    pub key: Option<String>,
+    pub value: Option<f64>,
+    /// Number of distinct homeservers in the group
+    pub homeservers: i64,
+}
+
+/// Daily values of a metric grouped by a dimension
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, ToSchema)]
+pub struct QueryResult {
+    pub dimension: Dimension,
+    pub metric: String,
+    pub function: AggregateFunction,
+    pub from: sqlx::types::time::Date,
+    pub to: sqlx::types::time::Date,
+    /// Ordered by value, largest first
+    pub groups: Vec<QueryGroup>,
+    /// Whether there were more groups than returned
+    pub truncated: bool,
+}
+
+/// A homeserver's position in a [`Ranking`]
+#[derive(Debug, Deserialize, Serialize, PartialEq, FromRow, Clone, ToSchema)]
+pub struct RankedHomeserver {
+    pub rank: i64,
+    pub homeserver: String,
+    pub value: f64,
+    /// Part of the total of all homeservers in scope
+    pub share: Option<f64>,
+}
+
+/// Homeservers ranked by a metric of `homeserver_daily`
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, ToSchema)]
+pub struct Ranking {
+    pub metric: String,
+    pub function: AggregateFunction,
+    pub from: sqlx::types::time::Date,
+    pub to: sqlx::types::time::Date,
+    pub server_context: Option<String>,
+    /// Total of all homeservers in scope, not just the ranked ones
+    pub total: Option<f64>,
+    pub homeservers: Vec<RankedHomeserver>,
+}
+
+/// Sort keys of the homeserver directory
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HomeserverSort {
+    #[default]
+    Homeserver,
+    FirstReport,
+    LastReport,
+    TotalUsers,
+    DailyActiveUsers,
+    MonthlyActiveUsers,
+}
+
+impl HomeserverSort {
+    /// Column of the directory to sort by
+    pub const fn column(self) -> &'static str {
+        match self {
+            Self::Homeserver => "homeserver",
+            Self::FirstReport => "first_report",
+            Self::LastReport => "last_report",
+            Self::TotalUsers => "total_users",
+            Self::DailyActiveUsers => "daily_active_users",
+            Self::MonthlyActiveUsers => "monthly_active_users",
+        }
+    }
+}
+
+/// Sort direction of listings
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub const fn sql(self) -> &'static str {
+        match self {
+            Self::Asc => "ASC",
+            Self::Desc => "DESC",
+        }
+    }
+}
+
+/// One page of a listing
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, ToSchema)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Number of items across all pages
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// Field names of a struct in declaration order, as seen by serde
+pub fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    /// Deserializer which only records the fields it is asked for
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(
+            self,
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            Err(serde::de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: serde::de::Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(serde::de::Error::custom("field names recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
 }
@@ -1,60 +1,399 @@
 use std::net::{IpAddr, SocketAddr};
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
+use axum::extract::rejection::JsonRejection;
 use axum::extract::{ConnectInfo, Path, Query, State};
-use axum::{Extension, Json, Router, response::IntoResponse};
-use axum::{routing::get, routing::put};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
 use axum_extra::TypedHeader;
 use axum_extra::headers::{Header, UserAgent};
 use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
-use http::{HeaderName, HeaderValue, StatusCode};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
 use log::info;
 use serde::Deserialize;
 use serde_json::json;
 use tokio::sync::mpsc;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
 use tracing::instrument;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
 
-use crate::model;
-use crate::settings::{DBSettings, ServerSettings};
+use crate::auth::{Actor, Admin, PublicListener, StatsScope};
+use crate::database::{AggregationScope, Generation};
+use crate::export::{self, Format};
+use crate::ingest::IngestRules;
+use crate::metrics;
+use crate::model::{self, Extrapolate};
+use crate::settings::{AggregationSettings, CorsPolicy, CorsSettings, DBSettings, ServerSettings};
 
 pub async fn run_server(
     settings: ServerSettings,
     db_settings: Arc<DBSettings>,
+    aggregation: Arc<AggregationSettings>,
     tx: mpsc::Sender<model::Report>,
 ) -> Result<()> {
-    let app = Router::new()
-        .route("/health", get(health_check))
-        .route("/report-usage-stats/push", put(save_report))
-        .route("/aggregated-stats/{day}", get(get_aggregated_stats))
-        .route(
-            "/aggregated-stats/{day}/{context}",
-            get(get_aggregated_stats_by_context),
+    let (public, internal) = listener_routers(&db_settings, &settings)?;
+
+    let rules = match &settings.ingest_rules {
+        Some(ingest_rules) => {
+            let rules = Arc::new(IngestRules::load(&ingest_rules.path)?);
+            tokio::spawn(crate::ingest::watch(
+                rules.clone(),
+                ingest_rules.clone(),
+                db_settings.clone(),
+            ));
+            rules
+        }
+        None => Arc::default(),
+    };
+    let server_settings = Arc::new(settings.clone());
+    let app = |router: ListenerRouter| {
+        router
+            .with_state(db_settings.clone())
+            .layer(Extension(tx.clone()))
+            .layer(Extension(rules.clone()))
+            .layer(Extension(aggregation.clone()))
+            .layer(Extension(server_settings.clone()))
+            .layer(OtelInResponseLayer)
+            .layer(OtelAxumLayer::default())
+    };
+
+    let public = serve_public(&settings, app(public));
+    let internal = async {
+        let (Some(admin_host), Some(internal)) = (&settings.admin_host, internal) else {
+            return Ok(());
+        };
+        let listener = tokio::net::TcpListener::bind(&admin_host.parse::<SocketAddr>()?).await?;
+        info!("Starting admin server on {admin_host}");
+        axum::serve(
+            listener,
+            app(internal).into_make_service_with_connect_info::<SocketAddr>(),
         )
-        .with_state(db_settings)
-        .layer(Extension(tx))
-        .layer(OtelInResponseLayer)
-        .layer(OtelAxumLayer::default())
-        .into_make_service_with_connect_info::<SocketAddr>();
+        .await
+        .context("admin server failed")
+    };
+    tokio::try_join!(public, internal)?;
+
+    Ok(())
+}
+
+/// Routes of a listener, still needing the database settings as state
+pub type ListenerRouter = Router<Arc<DBSettings>>;
 
-    let listener = tokio::net::TcpListener::bind(&settings.host.parse::<SocketAddr>()?).await?;
-    info!("Starting server on {}", settings.host);
-    axum::serve(listener, app).await?;
+/// Routers of the listener on `server.host` and, if `server.admin_host` is
+/// set, of the admin listener. The admin listener takes over health, metrics,
+/// the admin endpoints, on-demand aggregation and the API documentation,
+/// leaving the push endpoint and the aggregated stats on `server.host`.
+fn listener_routers(
+    db_settings: &Arc<DBSettings>,
+    settings: &ServerSettings,
+) -> Result<(ListenerRouter, Option<ListenerRouter>)> {
+    let (router, openapi) = api_router(db_settings, &settings.cors)?.split_for_parts();
+    let spec = openapi
+        .to_pretty_json()
+        .context("failed serializing the OpenAPI document")?;
+    let docs = Router::new().route(
+        "/openapi.json",
+        get(|| async move { ([(header::CONTENT_TYPE, "application/json")], spec) }),
+    );
+    #[cfg(feature = "openapi-viewer")]
+    let docs = docs.merge(
+        utoipa_swagger_ui::SwaggerUi::new("/docs")
+            .config(utoipa_swagger_ui::Config::from("/openapi.json")),
+    );
+
+    if settings.admin_host.is_none() {
+        return Ok((router.merge(docs), None));
+    }
+
+    let groups = RouteGroups::new(db_settings, &settings.cors)?;
+    let mut public = Router::from(groups.push);
+    if settings.stats_on_host {
+        public = public.merge(Router::from(groups.stats.clone()));
+    }
+    let internal = Router::from(groups.service.merge(groups.stats).merge(groups.admin)).merge(docs);
+
+    Ok((public.layer(Extension(PublicListener)), Some(internal)))
+}
+
+/// Serves `app` on `server.host`, over TLS if configured, and additionally
+/// over plain HTTP on `server.tls.http_host` if set
+async fn serve_public(settings: &ServerSettings, app: Router) -> Result<()> {
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let addr = settings.host.parse::<SocketAddr>()?;
+    let Some(tls) = &settings.tls else {
+        let listener = tokio::net::TcpListener::bind(&addr).await?;
+        info!("Starting server on {}", settings.host);
+        axum::serve(listener, app).await?;
+        return Ok(());
+    };
+
+    let config = crate::tls::load(tls).await?;
+    tokio::spawn(crate::tls::watch(config.clone(), tls.clone()));
+    let https = async {
+        info!("Starting HTTPS server on {}", settings.host);
+        axum_server::bind_rustls(addr, config)
+            .serve(app.clone())
+            .await
+            .context("HTTPS server failed")
+    };
+    let http = async {
+        let Some(http_host) = &tls.http_host else {
+            return Ok(());
+        };
+        let listener = tokio::net::TcpListener::bind(&http_host.parse::<SocketAddr>()?).await?;
+        info!("Starting HTTP server on {http_host}");
+        axum::serve(listener, app.clone())
+            .await
+            .context("HTTP server failed")
+    };
+    tokio::try_join!(https, http)?;
 
     Ok(())
 }
 
-/// Returns 200 OK for health checking
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Collects usage statistics of Synapse homeservers and aggregates them"),
+    modifiers(&BearerTokens),
+    // Query parameter schemas aren't collected from the routes
+    components(schemas(Format, model::HomeserverSort, model::SortOrder)),
+    tags(
+        (name = "reports", description = "Reports sent by homeservers"),
+        (name = "stats", description = "Aggregated stats, requires an API key unless `server.public_stats` is enabled"),
+        (name = "admin", description = "Per-homeserver data, requires the admin token or an API key with the `admin` scope"),
+        (name = "service", description = "Operation of the service"),
+    )
+)]
+struct ApiDoc;
+
+/// Adds the bearer tokens accepted by the API, the admin token and API keys
+struct BearerTokens;
+
+impl Modify for BearerTokens {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        for name in ["admin_token", "api_key"] {
+            components.add_security_scheme(
+                name,
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+/// Routes of the API, documented in the OpenAPI document served at
+/// `/openapi.json`
+fn api_router(
+    db_settings: &Arc<DBSettings>,
+    cors: &CorsSettings,
+) -> Result<OpenApiRouter<Arc<DBSettings>>> {
+    let mut openapi = ApiDoc::openapi();
+    // Taken from Cargo.toml, which doesn't declare a license
+    openapi.info.license = None;
+
+    let groups = RouteGroups::new(db_settings, cors)?;
+    Ok(OpenApiRouter::with_openapi(openapi)
+        .merge(groups.service)
+        .merge(groups.push)
+        .merge(groups.stats)
+        .merge(groups.admin))
+}
+
+/// Routes of the API by the listener and CORS policy they get
+struct RouteGroups {
+    /// Health and metrics
+    service: OpenApiRouter<Arc<DBSettings>>,
+    push: OpenApiRouter<Arc<DBSettings>>,
+    stats: OpenApiRouter<Arc<DBSettings>>,
+    admin: OpenApiRouter<Arc<DBSettings>>,
+}
+
+impl RouteGroups {
+    fn new(db_settings: &Arc<DBSettings>, cors: &CorsSettings) -> Result<Self> {
+        let service = OpenApiRouter::new()
+            .routes(routes!(health_check))
+            .routes(routes!(get_metrics));
+        let push = OpenApiRouter::new().routes(routes!(save_report));
+        let stats = OpenApiRouter::new()
+            .routes(routes!(get_aggregated_stats_range))
+            .routes(routes!(get_aggregated_stats))
+            .routes(routes!(get_aggregated_stats_by_context))
+            .routes(routes!(get_homeserver_churn))
+            .routes(routes!(get_contexts))
+            .routes(routes!(get_homeserver_churn_by_context))
+            .routes(routes!(get_aggregated_stats_by_context_range))
+            .route_layer(middleware::from_extractor_with_state::<StatsScope, _>(
+                db_settings.clone(),
+            ));
+        let admin = OpenApiRouter::new()
+            .routes(routes!(get_homeservers))
+            .routes(routes!(get_homeserver_history))
+            .routes(routes!(get_homeserver_ranking))
+            .routes(routes!(query_homeserver_daily))
+            .routes(routes!(get_api_keys, create_api_key))
+            .routes(routes!(rotate_api_key))
+            .routes(routes!(expire_api_key))
+            .routes(routes!(revoke_api_key))
+            .routes(routes!(get_ingest_rules))
+            .routes(routes!(get_flagged_reports))
+            .routes(routes!(confirm_flagged_report))
+            .routes(routes!(reject_flagged_report))
+            .routes(routes!(get_audit_log))
+            .routes(routes!(erase_homeserver));
+
+        Ok(Self {
+            service,
+            push: with_cors(push, cors.push.as_ref()).context("invalid server.cors.push")?,
+            stats: with_cors(stats, cors.stats.as_ref()).context("invalid server.cors.stats")?,
+            admin: with_cors(admin, cors.admin.as_ref()).context("invalid server.cors.admin")?,
+        })
+    }
+}
+
+/// Applies the CORS policy of a route group. Preflight requests are answered
+/// before any authentication.
+fn with_cors(
+    router: OpenApiRouter<Arc<DBSettings>>,
+    policy: Option<&CorsPolicy>,
+) -> Result<OpenApiRouter<Arc<DBSettings>>> {
+    let Some(policy) = policy else {
+        return Ok(router);
+    };
+    let wildcard = |values: &[String]| values.iter().any(|value| value == "*");
+    anyhow::ensure!(
+        !policy.allow_credentials
+            || !(wildcard(&policy.allowed_origins)
+                || wildcard(&policy.allowed_methods)
+                || wildcard(&policy.allowed_headers)),
+        "`*` can't be combined with allow_credentials"
+    );
+
+    let origins = if wildcard(&policy.allowed_origins) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            policy
+                .allowed_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid origin")?,
+        )
+    };
+    let methods = if wildcard(&policy.allowed_methods) {
+        AllowMethods::any()
+    } else {
+        AllowMethods::list(
+            policy
+                .allowed_methods
+                .iter()
+                .map(|method| method.parse::<http::Method>())
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid method")?,
+        )
+    };
+    let headers = if wildcard(&policy.allowed_headers) {
+        AllowHeaders::any()
+    } else {
+        AllowHeaders::list(
+            policy
+                .allowed_headers
+                .iter()
+                .map(|header| header.parse::<HeaderName>())
+                .collect::<Result<Vec<_>, _>>()
+                .context("invalid header")?,
+        )
+    };
+    let mut layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(policy.allow_credentials);
+    if let Some(max_age) = policy.max_age_seconds {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+
+    Ok(router.layer(layer))
+}
+
+/// Returns 200 OK for health checking, along with whether this replica is the
+/// one running the scheduled jobs
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "service",
+    responses(
+        (status = 200, description = "The database can be reached", example = json!({ "status": "ok", "leader": true })),
+        (status = 500, description = "The database can't be reached", example = json!({ "status": "unhealthy", "leader": false })),
+    )
+)]
 async fn health_check(State(db_settings): State<Arc<DBSettings>>) -> impl IntoResponse {
+    let leader = crate::leader::is_leader();
     if let Err(e) = crate::database::connect_pg_gracefully(&db_settings.url).await {
         log::error!("Database connection failed during health check: {e:?}");
-        StatusCode::INTERNAL_SERVER_ERROR
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "status": "unhealthy", "leader": leader })),
+        )
     } else {
-        StatusCode::OK
+        (
+            StatusCode::OK,
+            Json(json!({ "status": "ok", "leader": leader })),
+        )
     }
 }
 
+/// Metrics in the Prometheus text format, including the latest aggregated
+/// stats if `server.fleet_metrics` is enabled
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "service",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    )
+)]
+#[instrument(skip_all)]
+async fn get_metrics(
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Extension(tx): Extension<mpsc::Sender<model::Report>>,
+) -> Result<Response, (StatusCode, String)> {
+    let depth = tx.max_capacity() - tx.capacity();
+    metrics::CHANNEL_DEPTH.set(i64::try_from(depth).unwrap_or(i64::MAX));
+
+    let fleet = if settings.fleet_metrics {
+        Some(metrics::Fleet {
+            global: crate::database::get_latest_aggregated_stats(&db_settings)
+                .await
+                .map_err(|err| internal_error(&err))?,
+            contexts: crate::database::get_latest_aggregated_stats_by_context(&db_settings)
+                .await
+                .map_err(|err| internal_error(&err))?,
+        })
+    } else {
+        None
+    };
+
+    let body = metrics::render(fleet).map_err(|err| internal_error(&err))?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(prometheus::TEXT_FORMAT),
+        )],
+        body,
+    )
+        .into_response())
+}
+
 /// X-Forwarded-For header
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct XForwardedFor(IpAddr);
@@ -91,68 +430,1363 @@ impl Header for XForwardedFor {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone, Default, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct QueryParams {
+    /// Aggregate the day before returning it, requires the `admin` scope. The
+    /// `X-Aggregation-Status` response header tells whether this happened.
     generate: Option<bool>,
+    /// Scale summed metrics up to all homeservers by their coverage
+    extrapolate: Option<bool>,
+    /// Response format, overriding the `Accept` header
+    format: Option<Format>,
 }
 
-#[instrument]
+/// Renders aggregated stats of a single day in the negotiated format
+fn stats_response<T>(stats: T, params: &QueryParams, headers: &HeaderMap) -> Response
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    match Format::negotiate(params.format, headers) {
+        Format::Json => Json(stats).into_response(),
+        Format::Csv => match serde_json::to_value(stats) {
+            Ok(serde_json::Value::Object(row)) => {
+                export::csv_response(model::field_names::<T>().to_vec(), vec![row])
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        },
+    }
+}
+
+/// Response header telling the outcome of `?generate=true`
+const AGGREGATION_STATUS: HeaderName = HeaderName::from_static("x-aggregation-status");
+
+/// Aggregates `day` if `?generate=true` was given, recording it in the audit
+/// log as taken by `actor`, the one authorized by [`StatsScope`]
+async fn maybe_generate(
+    db_settings: &DBSettings,
+    aggregation: &AggregationSettings,
+    actor: Option<&Actor>,
+    scope: AggregationScope,
+    day: sqlx::types::time::Date,
+    generate: Option<bool>,
+) -> Result<Option<Generation>, StatusCode> {
+    if generate != Some(true) {
+        return Ok(None);
+    }
+    let run = async {
+        crate::database::generate(db_settings, aggregation, scope, day)
+            .await
+            .map_err(|err| internal_error(&err))
+    };
+    let generation = match actor {
+        Some(actor) => {
+            let parameters = json!({ "scope": scope.as_str(), "day": day.to_string() });
+            audited(db_settings, actor, "aggregation.generate", parameters, run).await
+        }
+        None => run.await,
+    };
+    generation.map(Some).map_err(|(status, _)| status)
+}
+
+/// Adds the outcome of `?generate=true` to the response headers
+fn with_generation(mut response: Response, generation: Option<Generation>) -> Response {
+    if let Some(generation) = generation {
+        let headers = response.headers_mut();
+        headers.insert(
+            AGGREGATION_STATUS,
+            HeaderValue::from_static(generation.as_str()),
+        );
+        if let Generation::Throttled(seconds) = generation {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+    }
+    response
+}
+
+/// Applies the `extrapolate` query parameter to aggregated stats
+fn maybe_extrapolate<T: Extrapolate>(mut stats: T, extrapolate: Option<bool>) -> T {
+    if extrapolate == Some(true) {
+        stats.extrapolate();
+    }
+    stats
+}
+
+#[utoipa::path(
+    get,
+    path = "/aggregated-stats/{day}",
+    tag = "stats",
+    security(("api_key" = ["stats:global"]), ("admin_token" = [])),
+    params(("day" = Date, Path, description = "Day as `YYYY-MM-DD`"), QueryParams),
+    responses(
+        (status = 200, description = "Aggregated stats of the day", headers(
+            ("X-Aggregation-Status" = String, description = "With `generate=true`: `generated`, `joined` if the day was being aggregated already, or `throttled` if it was aggregated recently"),
+            ("Retry-After" = u64, description = "Seconds until a throttled day can be aggregated again"),
+        ), content(
+            (model::AggregatedStats = "application/json"),
+            (String = "text/csv")
+        )),
+        (status = 404, description = "No stats for the day"),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope, or `admin` with `generate=true`", body = String),
+    )
+)]
+#[instrument(skip(aggregation, headers))]
 async fn get_aggregated_stats(
     State(db_settings): State<Arc<DBSettings>>,
+    Extension(aggregation): Extension<Arc<AggregationSettings>>,
+    actor: Option<Extension<Actor>>,
     Path(day): Path<sqlx::types::time::Date>,
     Query(params): Query<QueryParams>,
-) -> Result<Json<model::AggregatedStats>, StatusCode> {
-    if let Some(true) = params.generate {
-        if let Err(err) = crate::database::aggregate_stats(&db_settings, day).await {
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let generation = maybe_generate(
+        &db_settings,
+        &aggregation,
+        actor.as_ref().map(|Extension(actor)| actor),
+        AggregationScope::Global,
+        day,
+        params.generate,
+    )
+    .await?;
+
+    let stats = crate::database::get_aggregated_stats(&db_settings, day)
+        .await
+        .map_err(|err| {
             log::error!("{err:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    }
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok(Json(
-        crate::database::get_aggregated_stats(&db_settings, day)
-            .await
-            .map_err(|err| {
-                log::error!("{err:?}");
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
-            .ok_or(StatusCode::NOT_FOUND)?,
+    let stats = maybe_extrapolate(stats, params.extrapolate);
+    Ok(with_generation(
+        stats_response(stats, &params, &headers),
+        generation,
     ))
 }
 
-#[instrument]
+#[utoipa::path(
+    get,
+    path = "/aggregated-stats/{day}/{context}",
+    tag = "stats",
+    security(("api_key" = ["stats:context:{context}"]), ("admin_token" = [])),
+    params(
+        ("day" = Date, Path, description = "Day as `YYYY-MM-DD`"),
+        ("context" = String, Path, description = "Server context"),
+        QueryParams
+    ),
+    responses(
+        (status = 200, description = "Aggregated stats of the server context on the day", headers(
+            ("X-Aggregation-Status" = String, description = "With `generate=true`: `generated`, `joined` if the day was being aggregated already, or `throttled` if it was aggregated recently"),
+            ("Retry-After" = u64, description = "Seconds until a throttled day can be aggregated again"),
+        ), content(
+            (model::AggregatedStatsByContext = "application/json"),
+            (String = "text/csv")
+        )),
+        (status = 404, description = "No stats for the server context on the day"),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope, or `admin` with `generate=true`", body = String),
+    )
+)]
+#[instrument(skip(aggregation, headers))]
 async fn get_aggregated_stats_by_context(
     State(db_settings): State<Arc<DBSettings>>,
+    Extension(aggregation): Extension<Arc<AggregationSettings>>,
+    actor: Option<Extension<Actor>>,
     Path((day, context)): Path<(sqlx::types::time::Date, String)>,
     Query(params): Query<QueryParams>,
-) -> Result<Json<model::AggregatedStatsByContext>, StatusCode> {
-    if let Some(true) = params.generate {
-        if let Err(err) = crate::database::aggregate_stats_by_context(&db_settings, day).await {
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let generation = maybe_generate(
+        &db_settings,
+        &aggregation,
+        actor.as_ref().map(|Extension(actor)| actor),
+        AggregationScope::Context,
+        day,
+        params.generate,
+    )
+    .await?;
+
+    let stats = crate::database::get_aggregated_stats_by_context(&db_settings, day, context)
+        .await
+        .map_err(|err| {
             log::error!("{err:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let stats = maybe_extrapolate(stats, params.extrapolate);
+    Ok(with_generation(
+        stats_response(stats, &params, &headers),
+        generation,
+    ))
+}
+
+#[derive(Deserialize, Debug, Clone, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RangeParams {
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+    /// Comma separated list of fields to return, all of them by default
+    fields: Option<String>,
+    /// Scale summed metrics up to all homeservers by their coverage
+    extrapolate: Option<bool>,
+    /// Response format, overriding the `Accept` header
+    format: Option<Format>,
+}
+
+/// Checks a date range against the configured maximum number of days
+fn validate_range(
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+    max_range_days: i64,
+) -> Result<(), (StatusCode, String)> {
+    if from > to {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "`from` must not be after `to`".to_owned(),
+        ));
+    }
+    if (to - from).whole_days() >= max_range_days {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("ranges are limited to {max_range_days} days"),
+        ));
+    }
+    Ok(())
+}
+
+impl RangeParams {
+    fn validate(&self, max_range_days: i64) -> Result<(), (StatusCode, String)> {
+        validate_range(self.from, self.to, max_range_days)
+    }
+
+    /// Validated list of requested fields of `T`, `None` for all fields
+    fn fields<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<Option<Vec<&str>>, (StatusCode, String)> {
+        let Some(fields) = &self.fields else {
+            return Ok(None);
+        };
+        let known = model::field_names::<T>();
+        let fields = fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .collect::<Vec<_>>();
+        if let Some(unknown) = fields.iter().find(|field| !known.contains(field)) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("unknown field {unknown:?}"),
+            ));
+        }
+        Ok(Some(fields))
+    }
+}
+
+/// Builds the response for a date range from stats ordered by day
+fn stats_range<T>(
+    stats: Vec<T>,
+    params: &RangeParams,
+    day_of: impl Fn(&T) -> sqlx::types::time::Date,
+) -> Result<model::StatsRange, (StatusCode, String)>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Extrapolate,
+{
+    let fields = params.fields::<T>()?;
+    let days = stats.iter().map(day_of).collect::<Vec<_>>();
+
+    let mut rows = Vec::with_capacity(stats.len());
+    for stats in stats {
+        let stats = maybe_extrapolate(stats, params.extrapolate);
+        let serde_json::Value::Object(mut row) =
+            serde_json::to_value(stats).map_err(|err| internal_error(&err.into()))?
+        else {
+            return Err(internal_error(&anyhow::anyhow!("stats are not an object")));
+        };
+        if let Some(fields) = &fields {
+            row.retain(|key, _| {
+                key == "day" || key == "server_context" || fields.contains(&key.as_str())
+            });
+        }
+        rows.push(serde_json::Value::Object(row));
+    }
+
+    let mut missing_days = Vec::new();
+    let mut day = Some(params.from);
+    while let Some(current) = day.filter(|day| *day <= params.to) {
+        if !days.contains(&current) {
+            missing_days.push(current);
+        }
+        day = current.next_day();
+    }
+
+    Ok(model::StatsRange {
+        from: params.from,
+        to: params.to,
+        stats: rows,
+        missing_days,
+    })
+}
+
+/// Renders the stats of a date range in the negotiated format. CSV has one line
+/// per day with stats, so the missing days are left out.
+fn stats_range_response<T>(
+    stats: Vec<T>,
+    params: &RangeParams,
+    headers: &HeaderMap,
+    day_of: impl Fn(&T) -> sqlx::types::time::Date,
+) -> Result<Response, (StatusCode, String)>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Extrapolate,
+{
+    let range = stats_range(stats, params, day_of)?;
+    Ok(match Format::negotiate(params.format, headers) {
+        Format::Json => Json(range).into_response(),
+        Format::Csv => {
+            let rows = range
+                .stats
+                .into_iter()
+                .filter_map(|row| match row {
+                    serde_json::Value::Object(row) => Some(row),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let fields = params.fields::<T>()?;
+            let columns = model::field_names::<T>()
+                .iter()
+                .copied()
+                .filter(|column| {
+                    fields.as_ref().is_none_or(|fields| {
+                        *column == "day" || *column == "server_context" || fields.contains(column)
+                    })
+                })
+                .collect();
+            export::csv_response(columns, rows)
+        }
+    })
+}
+
+fn internal_error(err: &anyhow::Error) -> (StatusCode, String) {
+    log::error!("{err:?}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal server error".to_owned(),
+    )
+}
+
+/// Takes an administrative action and records it in the audit log, along with
+/// the error response if it failed. The action stands even if it can't be
+/// recorded.
+async fn audited<T>(
+    db_settings: &DBSettings,
+    actor: &Actor,
+    action: &str,
+    parameters: serde_json::Value,
+    run: impl Future<Output = Result<T, (StatusCode, String)>>,
+) -> Result<T, (StatusCode, String)> {
+    let result = run.await;
+    let error = result.as_ref().err().map(|(_, message)| message.as_str());
+    if let Err(err) =
+        crate::database::record_audit(db_settings, actor, action, &parameters, error).await
+    {
+        log::error!("{err:?}");
+    }
+    result
+}
+
+#[utoipa::path(
+    get,
+    path = "/aggregated-stats",
+    tag = "stats",
+    security(("api_key" = ["stats:global"]), ("admin_token" = [])),
+    params(RangeParams),
+    responses(
+        (status = 200, description = "Aggregated stats of every day of the range", content(
+            (model::StatsRange = "application/json"),
+            (String = "text/csv")
+        )),
+        (status = 400, description = "Invalid range or fields", body = String),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope", body = String),
+    )
+)]
+#[instrument(skip(settings, headers))]
+async fn get_aggregated_stats_range(
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Query(params): Query<RangeParams>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    params.validate(settings.max_range_days)?;
+
+    let stats = crate::database::get_aggregated_stats_range(&db_settings, params.from, params.to)
+        .await
+        .map_err(|err| internal_error(&err))?;
+
+    stats_range_response(stats, &params, &headers, |stats| stats.day)
+}
+
+#[utoipa::path(
+    get,
+    path = "/aggregated-stats-by-context/{context}",
+    tag = "stats",
+    security(("api_key" = ["stats:context:{context}"]), ("admin_token" = [])),
+    params(("context" = String, Path, description = "Server context"), RangeParams),
+    responses(
+        (status = 200, description = "Aggregated stats of the server context for every day of the range", content(
+            (model::StatsRange = "application/json"),
+            (String = "text/csv")
+        )),
+        (status = 400, description = "Invalid range or fields", body = String),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope", body = String),
+    )
+)]
+#[instrument(skip(settings, headers))]
+async fn get_aggregated_stats_by_context_range(
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Path(context): Path<String>,
+    Query(params): Query<RangeParams>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    params.validate(settings.max_range_days)?;
+
+    let stats = crate::database::get_aggregated_stats_by_context_range(
+        &db_settings,
+        context,
+        params.from,
+        params.to,
+    )
+    .await
+    .map_err(|err| internal_error(&err))?;
+
+    stats_range_response(stats, &params, &headers, |stats| stats.day)
+}
+
+/// Page size used if no `limit` is given
+const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Largest accepted `limit`
+const MAX_PAGE_SIZE: i64 = 1000;
+
+#[derive(Deserialize, Debug, Clone, Default, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl PageParams {
+    /// Validated limit and offset
+    fn validate(&self) -> Result<(i64, i64), (StatusCode, String)> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        let offset = self.offset.unwrap_or(0);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("`limit` must be between 1 and {MAX_PAGE_SIZE}"),
+            ));
+        }
+        if offset < 0 {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "`offset` must not be negative".to_owned(),
+            ));
         }
+        Ok((limit, offset))
+    }
+}
+
+/// Lists the server contexts seen in the aggregated stats
+#[utoipa::path(
+    get,
+    path = "/contexts",
+    tag = "stats",
+    security(("api_key" = ["stats:global"]), ("admin_token" = [])),
+    params(PageParams),
+    responses(
+        (status = 200, description = "Server contexts ordered by name", body = model::Page<model::ContextSummary>),
+        (status = 400, description = "Invalid paging", body = String),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope", body = String),
+    )
+)]
+#[instrument]
+async fn get_contexts(
+    State(db_settings): State<Arc<DBSettings>>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<model::Page<model::ContextSummary>>, (StatusCode, String)> {
+    let (limit, offset) = params.validate()?;
+    let (items, total) = crate::database::get_contexts(&db_settings, limit, offset)
+        .await
+        .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(model::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[derive(Deserialize, Debug, Clone, Default, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HomeserverParams {
+    status: Option<model::HomeserverStatus>,
+    server_context: Option<String>,
+    /// Synapse version, e.g. `1.99.0`
+    version: Option<String>,
+    /// Part of the homeserver name
+    search: Option<String>,
+    #[serde(default)]
+    sort: model::HomeserverSort,
+    #[serde(default)]
+    order: model::SortOrder,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// Lists the homeservers which sent reports, along with their status
+#[utoipa::path(
+    get,
+    path = "/admin/homeservers",
+    tag = "admin",
+    params(HomeserverParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "Homeservers matching the filters", body = model::Page<model::HomeserverSummary>),
+        (status = 400, description = "Invalid filters or paging", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument(skip(aggregation))]
+async fn get_homeservers(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(aggregation): Extension<Arc<AggregationSettings>>,
+    Query(params): Query<HomeserverParams>,
+) -> Result<Json<model::Page<model::HomeserverSummary>>, (StatusCode, String)> {
+    let (limit, offset) = PageParams {
+        limit: params.limit,
+        offset: params.offset,
+    }
+    .validate()?;
+    let filter = crate::database::HomeserverFilter {
+        status: params.status,
+        server_context: params.server_context,
+        version: params.version,
+        search: params.search,
+    };
+    let (items, total) = crate::database::get_homeservers(
+        &db_settings,
+        &aggregation,
+        &filter,
+        (params.sort, params.order),
+        limit,
+        offset,
+    )
+    .await
+    .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(model::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[derive(Deserialize, Debug, Clone, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DayRangeParams {
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+}
+
+/// New, returning and churned homeservers per day
+#[utoipa::path(
+    get,
+    path = "/churn",
+    tag = "stats",
+    security(("api_key" = ["stats:global"]), ("admin_token" = [])),
+    params(DayRangeParams),
+    responses(
+        (status = 200, description = "Homeserver churn of every aggregated day of the range", body = Vec<model::HomeserverChurn>),
+        (status = 400, description = "Invalid range", body = String),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope", body = String),
+    )
+)]
+#[instrument(skip(settings))]
+async fn get_homeserver_churn(
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Query(params): Query<DayRangeParams>,
+) -> Result<Json<Vec<model::HomeserverChurn>>, (StatusCode, String)> {
+    validate_range(params.from, params.to, settings.max_range_days)?;
+
+    let churn = crate::database::get_homeserver_churn(&db_settings, params.from, params.to)
+        .await
+        .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(churn))
+}
+
+/// New, returning and churned homeservers of a server context per day
+#[utoipa::path(
+    get,
+    path = "/contexts/{context}/churn",
+    tag = "stats",
+    security(("api_key" = ["stats:context:{context}"]), ("admin_token" = [])),
+    params(("context" = String, Path, description = "Server context"), DayRangeParams),
+    responses(
+        (status = 200, description = "Homeserver churn of the server context for every aggregated day of the range", body = Vec<model::HomeserverChurnByContext>),
+        (status = 400, description = "Invalid range", body = String),
+        (status = 401, description = "Missing or invalid API key", body = String),
+        (status = 403, description = "The API key lacks the scope", body = String),
+    )
+)]
+#[instrument(skip(settings))]
+async fn get_homeserver_churn_by_context(
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Path(context): Path<String>,
+    Query(params): Query<DayRangeParams>,
+) -> Result<Json<Vec<model::HomeserverChurnByContext>>, (StatusCode, String)> {
+    validate_range(params.from, params.to, settings.max_range_days)?;
+
+    let churn = crate::database::get_homeserver_churn_by_context(
+        &db_settings,
+        context,
+        params.from,
+        params.to,
+    )
+    .await
+    .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(churn))
+}
+
+/// Daily values of a single homeserver
+#[utoipa::path(
+    get,
+    path = "/admin/homeservers/{name}/history",
+    tag = "admin",
+    params(("name" = String, Path, description = "Name of the homeserver"), DayRangeParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "Daily values of the homeserver for every day of the range", body = Vec<model::HomeserverDay>),
+        (status = 400, description = "Invalid range", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument(skip(settings))]
+async fn get_homeserver_history(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Path(name): Path<String>,
+    Query(params): Query<DayRangeParams>,
+) -> Result<Json<Vec<model::HomeserverDay>>, (StatusCode, String)> {
+    validate_range(params.from, params.to, settings.max_range_days)?;
+
+    let history =
+        crate::database::get_homeserver_history(&db_settings, &name, params.from, params.to)
+            .await
+            .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(history))
+}
+
+/// Number of ranked homeservers if no `limit` is given
+const DEFAULT_RANKING_SIZE: i64 = 10;
+
+#[derive(Deserialize, Debug, Clone, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RankingParams {
+    /// One of [`model::DAILY_METRICS`]
+    metric: String,
+    /// Aggregate function over the days of the range, `sum` by default
+    #[serde(default)]
+    function: model::AggregateFunction,
+    from: sqlx::types::time::Date,
+    /// Last day of the range, only `from` is ranked if not given
+    to: Option<sqlx::types::time::Date>,
+    server_context: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Ranks homeservers by a metric of their daily values, so that the totals
+/// reconcile with the aggregated stats
+#[utoipa::path(
+    get,
+    path = "/admin/rankings",
+    tag = "admin",
+    params(RankingParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "The top homeservers", body = model::Ranking),
+        (status = 400, description = "Invalid metric, range or limit", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument(skip(settings))]
+async fn get_homeserver_ranking(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Query(params): Query<RankingParams>,
+) -> Result<Json<model::Ranking>, (StatusCode, String)> {
+    let to = params.to.unwrap_or(params.from);
+    validate_range(params.from, to, settings.max_range_days)?;
+    if !model::DAILY_METRICS.contains(&params.metric.as_str()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("unknown metric {:?}", params.metric),
+        ));
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_RANKING_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("`limit` must be between 1 and {MAX_PAGE_SIZE}"),
+        ));
+    }
+
+    let (homeservers, total) = crate::database::get_homeserver_ranking(
+        &db_settings,
+        &params.metric,
+        params.function,
+        (params.from, to),
+        params.server_context.as_deref(),
+        limit,
+    )
+    .await
+    .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(model::Ranking {
+        metric: params.metric,
+        function: params.function,
+        from: params.from,
+        to,
+        server_context: params.server_context,
+        total,
+        homeservers,
+    }))
+}
+
+#[derive(Deserialize, Debug, Clone, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GroupQueryParams {
+    dimension: model::Dimension,
+    /// One of [`model::DAILY_METRICS`]
+    metric: String,
+    #[serde(default)]
+    function: model::AggregateFunction,
+    from: sqlx::types::time::Date,
+    to: sqlx::types::time::Date,
+    server_context: Option<String>,
+}
+
+/// Groups the daily values of homeservers by a whitelisted dimension
+#[utoipa::path(
+    get,
+    path = "/admin/query",
+    tag = "admin",
+    params(GroupQueryParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "Groups ordered by value", body = model::QueryResult),
+        (status = 400, description = "Invalid dimension, metric or range", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+        (status = 503, description = "The query took too long", body = String),
+    )
+)]
+#[instrument(skip(settings))]
+async fn query_homeserver_daily(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(settings): Extension<Arc<ServerSettings>>,
+    Query(params): Query<GroupQueryParams>,
+) -> Result<Json<model::QueryResult>, (StatusCode, String)> {
+    validate_range(params.from, params.to, settings.max_range_days)?;
+    if !model::DAILY_METRICS.contains(&params.metric.as_str()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("unknown metric {:?}", params.metric),
+        ));
     }
 
-    Ok(Json(
-        crate::database::get_aggregated_stats_by_context(&db_settings, day, context)
+    let query = crate::database::GroupQuery {
+        dimension: params.dimension,
+        metric: &params.metric,
+        function: params.function,
+        from: params.from,
+        to: params.to,
+        server_context: params.server_context.as_deref(),
+    };
+    let (groups, truncated) = crate::database::query_homeserver_daily(
+        &db_settings,
+        &query,
+        settings.query_max_groups,
+        settings.query_timeout_ms,
+    )
+    .await
+    .map_err(|err| {
+        if crate::database::is_query_canceled(&err) {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the query took too long, try a shorter range".to_owned(),
+            )
+        } else {
+            internal_error(&err)
+        }
+    })?;
+
+    Ok(Json(model::QueryResult {
+        dimension: params.dimension,
+        metric: params.metric,
+        function: params.function,
+        from: params.from,
+        to: params.to,
+        groups,
+        truncated,
+    }))
+}
+
+/// Lists all API keys, including the expired and revoked ones
+#[utoipa::path(
+    get,
+    path = "/admin/keys",
+    tag = "admin",
+    params(PageParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "API keys ordered by creation", body = model::Page<model::ApiKey>),
+        (status = 400, description = "Invalid paging", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument]
+async fn get_api_keys(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<model::Page<model::ApiKey>>, (StatusCode, String)> {
+    let (limit, offset) = params.validate()?;
+    let (items, total) = crate::database::get_api_keys(&db_settings, limit, offset)
+        .await
+        .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(model::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// Creates an API key. Its secret is only part of this response.
+#[utoipa::path(
+    post,
+    path = "/admin/keys",
+    tag = "admin",
+    request_body = model::NewApiKey,
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 201, description = "The new API key", body = model::ApiKeySecret),
+        (status = 400, description = "Invalid label or scopes", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument]
+async fn create_api_key(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Json(key): Json<model::NewApiKey>,
+) -> Result<(StatusCode, Json<model::ApiKeySecret>), (StatusCode, String)> {
+    audited(&db_settings, &actor, "api_key.create", json!(key), async {
+        crate::auth::validate_new_key(&key)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        let (secret, hash) = crate::auth::generate_key();
+        let key = crate::database::create_api_key(&db_settings, &key, &hash)
+            .await
+            .map_err(|err| internal_error(&err))?;
+        info!("Created API key {} ({})", key.id, key.label);
+
+        Ok((
+            StatusCode::CREATED,
+            Json(model::ApiKeySecret { key, secret }),
+        ))
+    })
+    .await
+}
+
+/// Response for an API key which doesn't exist
+fn no_such_key(id: i64) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("no API key with ID {id}"))
+}
+
+/// Replaces the secret of an API key, the old one stops working right away
+#[utoipa::path(
+    post,
+    path = "/admin/keys/{id}/rotate",
+    tag = "admin",
+    params(("id" = i64, Path, description = "ID of the API key")),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "The API key with its new secret", body = model::ApiKeySecret),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+        (status = 404, description = "No such API key, or it has been revoked", body = String),
+    )
+)]
+#[instrument]
+async fn rotate_api_key(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Path(id): Path<i64>,
+) -> Result<Json<model::ApiKeySecret>, (StatusCode, String)> {
+    audited(
+        &db_settings,
+        &actor,
+        "api_key.rotate",
+        json!({ "id": id }),
+        async {
+            let (secret, hash) = crate::auth::generate_key();
+            let key = crate::database::rotate_api_key(&db_settings, id, &hash)
+                .await
+                .map_err(|err| internal_error(&err))?
+                .ok_or_else(|| no_such_key(id))?;
+            info!("Rotated API key {} ({})", key.id, key.label);
+
+            Ok(Json(model::ApiKeySecret { key, secret }))
+        },
+    )
+    .await
+}
+
+/// Sets or removes the expiry of an API key
+#[utoipa::path(
+    put,
+    path = "/admin/keys/{id}/expiry",
+    tag = "admin",
+    params(("id" = i64, Path, description = "ID of the API key")),
+    request_body = model::ApiKeyExpiry,
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "The updated API key", body = model::ApiKey),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+        (status = 404, description = "No such API key", body = String),
+    )
+)]
+#[instrument]
+async fn expire_api_key(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Path(id): Path<i64>,
+    Json(expiry): Json<model::ApiKeyExpiry>,
+) -> Result<Json<model::ApiKey>, (StatusCode, String)> {
+    let mut parameters = json!(expiry);
+    parameters["id"] = json!(id);
+    audited(&db_settings, &actor, "api_key.expire", parameters, async {
+        let key = crate::database::expire_api_key(&db_settings, id, expiry.expires_at)
             .await
-            .map_err(|err| {
+            .map_err(|err| internal_error(&err))?
+            .ok_or_else(|| no_such_key(id))?;
+
+        Ok(Json(key))
+    })
+    .await
+}
+
+/// Revokes an API key for good
+#[utoipa::path(
+    delete,
+    path = "/admin/keys/{id}",
+    tag = "admin",
+    params(("id" = i64, Path, description = "ID of the API key")),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "The revoked API key", body = model::ApiKey),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+        (status = 404, description = "No such API key", body = String),
+    )
+)]
+#[instrument]
+async fn revoke_api_key(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Path(id): Path<i64>,
+) -> Result<Json<model::ApiKey>, (StatusCode, String)> {
+    audited(
+        &db_settings,
+        &actor,
+        "api_key.revoke",
+        json!({ "id": id }),
+        async {
+            let key = crate::database::revoke_api_key(&db_settings, id)
+                .await
+                .map_err(|err| internal_error(&err))?
+                .ok_or_else(|| no_such_key(id))?;
+            info!("Revoked API key {} ({})", key.id, key.label);
+
+            Ok(Json(key))
+        },
+    )
+    .await
+}
+
+/// Lists the ingest rules in effect, along with the number of reports they
+/// matched
+#[utoipa::path(
+    get,
+    path = "/admin/ingest-rules",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "Ingest rules in the order they are evaluated", body = Vec<model::IngestRuleHits>),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument]
+async fn get_ingest_rules(
+    _: Admin,
+    Extension(rules): Extension<Arc<IngestRules>>,
+) -> Json<Vec<model::IngestRuleHits>> {
+    Json(rules.hits())
+}
+
+/// Lists the reports held back from the aggregation by anomaly detection
+#[utoipa::path(
+    get,
+    path = "/admin/flagged-reports",
+    tag = "admin",
+    params(PageParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "Flagged reports, oldest first", body = model::Page<model::FlaggedReport>),
+        (status = 400, description = "Invalid paging", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument]
+async fn get_flagged_reports(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<model::Page<model::FlaggedReport>>, (StatusCode, String)> {
+    let (limit, offset) = params.validate()?;
+    let (items, total) = crate::database::get_flagged_reports(&db_settings, limit, offset)
+        .await
+        .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(model::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// Response for a report which doesn't exist or isn't waiting for review
+fn no_such_flagged_report(id: i64) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("no flagged report with ID {id}"),
+    )
+}
+
+/// Confirms a flagged report, and aggregates its day again to include it
+#[utoipa::path(
+    post,
+    path = "/admin/flagged-reports/{id}/confirm",
+    tag = "admin",
+    params(("id" = i64, Path, description = "ID of the report")),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "The confirmed report", body = model::FlaggedReport),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+        (status = 404, description = "No such report waiting for review", body = String),
+    )
+)]
+#[instrument]
+async fn confirm_flagged_report(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(aggregation): Extension<Arc<AggregationSettings>>,
+    Path(id): Path<i64>,
+) -> Result<Json<model::FlaggedReport>, (StatusCode, String)> {
+    audited(
+        &db_settings,
+        &actor,
+        "report.confirm",
+        json!({ "id": id }),
+        async {
+            let report =
+                crate::database::review_report(&db_settings, id, model::ReviewStatus::Confirmed)
+                    .await
+                    .map_err(|err| internal_error(&err))?
+                    .ok_or_else(|| no_such_flagged_report(id))?;
+            info!("Confirmed flagged report {id} of {:?}", report.homeserver);
+
+            if let Some(day) = report.local_timestamp.map(time::OffsetDateTime::date) {
+                crate::database::aggregate_day(&db_settings, &aggregation.daily_strategy, day)
+                    .await
+                    .map_err(|err| internal_error(&err))?;
+                crate::database::refresh_message_totals(&db_settings, day)
+                    .await
+                    .map_err(|err| internal_error(&err))?;
+                // The homeserver is active on `day` and may have churned
+                // `churn_days` later, days still to come are left to the loop
+                let today = time::OffsetDateTime::now_utc().date();
+                let churned = day.checked_add(time::Duration::days(aggregation.churn_days));
+                for day in [Some(day), churned].into_iter().flatten() {
+                    if day <= today {
+                        crate::database::aggregate_churn(&db_settings, aggregation.churn_days, day)
+                            .await
+                            .map_err(|err| internal_error(&err))?;
+                    }
+                }
+            }
+
+            Ok(Json(report))
+        },
+    )
+    .await
+}
+
+/// Rejects a flagged report, which keeps it out of the aggregation for good
+#[utoipa::path(
+    post,
+    path = "/admin/flagged-reports/{id}/reject",
+    tag = "admin",
+    params(("id" = i64, Path, description = "ID of the report")),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "The rejected report", body = model::FlaggedReport),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+        (status = 404, description = "No such report waiting for review", body = String),
+    )
+)]
+#[instrument]
+async fn reject_flagged_report(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Path(id): Path<i64>,
+) -> Result<Json<model::FlaggedReport>, (StatusCode, String)> {
+    audited(
+        &db_settings,
+        &actor,
+        "report.reject",
+        json!({ "id": id }),
+        async {
+            let report =
+                crate::database::review_report(&db_settings, id, model::ReviewStatus::Rejected)
+                    .await
+                    .map_err(|err| internal_error(&err))?
+                    .ok_or_else(|| no_such_flagged_report(id))?;
+            info!("Rejected flagged report {id} of {:?}", report.homeserver);
+
+            Ok(Json(report))
+        },
+    )
+    .await
+}
+
+/// Erases the data of a homeserver on request of its operator. Deletes its
+/// reports, all of them or those of a range of days, and aggregates the days
+/// of the deleted reports again, see [`crate::database::aggregate_pending`].
+/// A tombstone tells whether its reports are refused from then on, as of the
+/// latest erasure.
+#[utoipa::path(
+    post,
+    path = "/admin/homeservers/{name}/erase",
+    tag = "admin",
+    params(("name" = String, Path, description = "Name of the homeserver")),
+    request_body = model::ErasureRequest,
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "What was erased", body = model::Erasure),
+        (status = 202, description = "What was erased, with `pending_days` left to the scheduled jobs to aggregate again", body = model::Erasure),
+        (status = 400, description = "Invalid range", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument(skip(aggregation))]
+async fn erase_homeserver(
+    Admin(actor): Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Extension(aggregation): Extension<Arc<AggregationSettings>>,
+    Path(name): Path<String>,
+    Json(request): Json<model::ErasureRequest>,
+) -> Result<(StatusCode, Json<model::Erasure>), (StatusCode, String)> {
+    let mut parameters = json!(request);
+    parameters["homeserver"] = json!(name);
+    audited(
+        &db_settings,
+        &actor,
+        "homeserver.erase",
+        parameters,
+        async {
+            if let (Some(from), Some(to)) = (request.from, request.to)
+                && from > to
+            {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "`from` must not be after `to`".to_owned(),
+                ));
+            }
+            let mut erasure = crate::database::erase_homeserver(&db_settings, &name, &request)
+                .await
+                .map_err(|err| internal_error(&err))?;
+            info!(
+                "Erased {} reports of {name} on {} days",
+                erasure.reports_deleted,
+                erasure.days.len()
+            );
+
+            if let Err(err) =
+                crate::database::aggregate_pending(&db_settings, &aggregation, &erasure.days).await
+            {
                 log::error!("{err:?}");
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
-            .ok_or(StatusCode::NOT_FOUND)?,
-    ))
+                erasure.pending_days =
+                    match crate::database::pending_aggregations(&db_settings).await {
+                        Ok(pending) => erasure
+                            .days
+                            .iter()
+                            .copied()
+                            .filter(|day| pending.contains(day))
+                            .collect(),
+                        Err(err) => {
+                            log::error!("{err:?}");
+                            erasure.days.clone()
+                        }
+                    };
+                log::warn!(
+                    "Days left to aggregate after erasing {name}: {:?}",
+                    erasure.pending_days
+                );
+            }
+
+            let status = if erasure.pending_days.is_empty() {
+                StatusCode::OK
+            } else {
+                StatusCode::ACCEPTED
+            };
+            Ok((status, Json(erasure)))
+        },
+    )
+    .await
+}
+
+#[derive(Deserialize, Debug, Clone, Default, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditLogParams {
+    /// Label of the API key, `admin_token`, `cli:<user>` or `system`
+    actor: Option<String>,
+    /// Action, e.g. `api_key.revoke`
+    action: Option<String>,
+    outcome: Option<model::AuditOutcome>,
+    /// Entries from then on, as RFC 3339 time
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<time::OffsetDateTime>,
+    /// Entries before then, as RFC 3339 time
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    until: Option<time::OffsetDateTime>,
+    limit: Option<i64>,
+    offset: Option<i64>,
 }
 
-#[instrument(skip(tx, report))]
+/// Lists the administrative actions taken, along with who took them and how
+/// they turned out
+#[utoipa::path(
+    get,
+    path = "/admin/audit-log",
+    tag = "admin",
+    params(AuditLogParams),
+    security(("admin_token" = []), ("api_key" = ["admin"])),
+    responses(
+        (status = 200, description = "Audit log entries matching the filters, newest first", body = model::Page<model::AuditEntry>),
+        (status = 400, description = "Invalid filters or paging", body = String),
+        (status = 401, description = "Missing or invalid bearer token", body = String),
+        (status = 403, description = "The API key lacks the `admin` scope", body = String),
+    )
+)]
+#[instrument]
+async fn get_audit_log(
+    _: Admin,
+    State(db_settings): State<Arc<DBSettings>>,
+    Query(params): Query<AuditLogParams>,
+) -> Result<Json<model::Page<model::AuditEntry>>, (StatusCode, String)> {
+    let (limit, offset) = PageParams {
+        limit: params.limit,
+        offset: params.offset,
+    }
+    .validate()?;
+    let filter = crate::database::AuditFilter {
+        actor: params.actor,
+        action: params.action,
+        outcome: params.outcome,
+        since: params.since,
+        until: params.until,
+    };
+    let (items, total) = crate::database::get_audit_log(&db_settings, &filter, limit, offset)
+        .await
+        .map_err(|err| internal_error(&err))?;
+
+    Ok(Json(model::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/report-usage-stats/push",
+    tag = "reports",
+    request_body = model::Report,
+    responses(
+        (status = 200, description = "The report was accepted", example = json!({})),
+        (status = 400, description = "Malformed report"),
+        (status = 403, description = "The report was rejected by an ingest rule", body = String),
+        (status = 422, description = "Invalid report"),
+    )
+)]
+#[instrument(skip(tx, rules, report))]
 async fn save_report(
     tx: Extension<mpsc::Sender<model::Report>>,
+    Extension(rules): Extension<Arc<IngestRules>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     forwarded_addr: Option<TypedHeader<XForwardedFor>>,
     user_agent: Option<TypedHeader<UserAgent>>,
-    report: Json<model::Report>,
-) -> (StatusCode, Json<serde_json::Value>) {
-    let mut report = report;
+    report: Result<Json<model::Report>, JsonRejection>,
+) -> Response {
+    let mut report = match report {
+        Ok(report) => report,
+        Err(rejection) => {
+            metrics::REPORTS_REJECTED
+                .with_label_values(&["invalid"])
+                .inc();
+            return rejection.into_response();
+        }
+    };
 
     // for tests, make it possible to not always set the local timestamp
     if report.local_timestamp.is_none() {
@@ -164,11 +1798,33 @@ async fn save_report(
                 .expect("replace millisecond")
         });
     }
+    let forwarded_addr = forwarded_addr.map(|TypedHeader(forwarded_addr)| forwarded_addr.0);
     report.remote_addr = Some(addr.to_string());
-    report.forwarded_for =
-        forwarded_addr.map(|TypedHeader(forwarded_addr)| forwarded_addr.0.to_string());
+    report.forwarded_for = forwarded_addr.map(|forwarded_addr| forwarded_addr.to_string());
     report.user_agent = user_agent.map(|TypedHeader(user_agent)| user_agent.to_string());
 
+    // Behind a reverse proxy, the client is the forwarded address
+    match rules.evaluate(&report, forwarded_addr.unwrap_or_else(|| addr.ip())) {
+        Some(model::IngestAction::Drop) => {
+            metrics::REPORTS_REJECTED
+                .with_label_values(&["dropped"])
+                .inc();
+            return (StatusCode::OK, Json(json!({}))).into_response();
+        }
+        Some(model::IngestAction::Reject) => {
+            metrics::REPORTS_REJECTED
+                .with_label_values(&["rejected"])
+                .inc();
+            return (
+                StatusCode::FORBIDDEN,
+                "the report was rejected by an ingest rule".to_owned(),
+            )
+                .into_response();
+        }
+        Some(model::IngestAction::Exclude) => report.excluded = true,
+        Some(model::IngestAction::Allow) | None => {}
+    }
+
     if let Err(err) = tx
         .send(report.0)
         .await
@@ -177,7 +1833,8 @@ async fn save_report(
         log::error!("{err:?}");
         process::exit(-1);
     }
-    (StatusCode::OK, Json(json!({})))
+    metrics::REPORTS_RECEIVED.inc();
+    (StatusCode::OK, Json(json!({}))).into_response()
 }
 
 #[cfg(test)]
@@ -185,27 +1842,73 @@ pub mod tests {
     use std::net::SocketAddr;
     use std::sync::Arc;
 
+    use axum::extract::rejection::JsonRejection;
     use axum::extract::{Path, State};
-    use axum::{Json, extract, response::IntoResponse};
+    use axum::response::{IntoResponse, Response};
+    use axum::{Json, extract};
     use axum_extra::TypedHeader;
     use axum_extra::headers::UserAgent;
-    use http::StatusCode;
+    use http::{HeaderMap, StatusCode};
     use tokio::sync::mpsc;
 
+    use crate::auth::{Actor, Admin};
+    use crate::ingest::IngestRules;
     use crate::model;
-    use crate::server::QueryParams;
-    use crate::settings::DBSettings;
+    use crate::server::{
+        DayRangeParams, GroupQueryParams, HomeserverParams, PageParams, QueryParams, RangeParams,
+        RankingParams,
+    };
+    use crate::settings::{AggregationSettings, CorsSettings, DBSettings, ServerSettings};
 
     use super::XForwardedFor;
 
+    pub fn openapi() -> utoipa::openapi::OpenApi {
+        super::api_router(
+            &Arc::new(DBSettings { url: String::new() }),
+            &CorsSettings::default(),
+        )
+        .expect("API router")
+        .into_openapi()
+    }
+
+    /// All routes, guarded like in production
+    pub fn router(db_settings: &Arc<DBSettings>) -> axum::Router<Arc<DBSettings>> {
+        router_with_cors(db_settings, &CorsSettings::default()).expect("API router")
+    }
+
+    /// Routers of the public and the admin listener
+    pub fn listener_routers(
+        db_settings: &Arc<DBSettings>,
+        settings: &ServerSettings,
+    ) -> anyhow::Result<(super::ListenerRouter, Option<super::ListenerRouter>)> {
+        super::listener_routers(db_settings, settings)
+    }
+
+    /// All routes with the given CORS policies
+    pub fn router_with_cors(
+        db_settings: &Arc<DBSettings>,
+        cors: &CorsSettings,
+    ) -> anyhow::Result<axum::Router<Arc<DBSettings>>> {
+        Ok(super::api_router(db_settings, cors)?.split_for_parts().0)
+    }
+
     pub async fn save_report(
         tx: extract::Extension<mpsc::Sender<model::Report>>,
+        rules: extract::Extension<Arc<IngestRules>>,
         addr: extract::ConnectInfo<SocketAddr>,
         forwarded_addr: Option<TypedHeader<XForwardedFor>>,
         user_agent: Option<TypedHeader<UserAgent>>,
-        report: Json<model::Report>,
-    ) -> (StatusCode, Json<serde_json::Value>) {
-        super::save_report(tx, addr, forwarded_addr, user_agent, report).await
+        report: Result<Json<model::Report>, JsonRejection>,
+    ) -> Response {
+        super::save_report(tx, rules, addr, forwarded_addr, user_agent, report).await
+    }
+
+    pub async fn get_metrics(
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        tx: extract::Extension<mpsc::Sender<model::Report>>,
+    ) -> Result<Response, (StatusCode, String)> {
+        super::get_metrics(db_settings, settings, tx).await
     }
 
     pub async fn health_check(db_settings: State<Arc<DBSettings>>) -> impl IntoResponse {
@@ -214,17 +1917,118 @@ pub mod tests {
 
     pub async fn get_aggregated_stats(
         db_settings: State<Arc<DBSettings>>,
+        aggregation: extract::Extension<Arc<AggregationSettings>>,
+        actor: Option<extract::Extension<Actor>>,
         day: Path<sqlx::types::time::Date>,
         params: extract::Query<QueryParams>,
-    ) -> Result<Json<model::AggregatedStats>, StatusCode> {
-        super::get_aggregated_stats(db_settings, day, params).await
+        headers: HeaderMap,
+    ) -> Result<Response, StatusCode> {
+        super::get_aggregated_stats(db_settings, aggregation, actor, day, params, headers).await
+    }
+
+    pub async fn get_aggregated_stats_range(
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        params: extract::Query<RangeParams>,
+        headers: HeaderMap,
+    ) -> Result<Response, (StatusCode, String)> {
+        super::get_aggregated_stats_range(db_settings, settings, params, headers).await
+    }
+
+    pub async fn get_aggregated_stats_by_context_range(
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        context: Path<String>,
+        params: extract::Query<RangeParams>,
+        headers: HeaderMap,
+    ) -> Result<Response, (StatusCode, String)> {
+        super::get_aggregated_stats_by_context_range(
+            db_settings,
+            settings,
+            context,
+            params,
+            headers,
+        )
+        .await
+    }
+
+    pub async fn get_contexts(
+        db_settings: State<Arc<DBSettings>>,
+        params: extract::Query<PageParams>,
+    ) -> Result<Json<model::Page<model::ContextSummary>>, (StatusCode, String)> {
+        super::get_contexts(db_settings, params).await
+    }
+
+    pub async fn get_homeservers(
+        admin: Admin,
+        db_settings: State<Arc<DBSettings>>,
+        aggregation: extract::Extension<Arc<AggregationSettings>>,
+        params: extract::Query<HomeserverParams>,
+    ) -> Result<Json<model::Page<model::HomeserverSummary>>, (StatusCode, String)> {
+        super::get_homeservers(admin, db_settings, aggregation, params).await
+    }
+
+    pub async fn get_homeserver_churn(
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        params: extract::Query<DayRangeParams>,
+    ) -> Result<Json<Vec<model::HomeserverChurn>>, (StatusCode, String)> {
+        super::get_homeserver_churn(db_settings, settings, params).await
+    }
+
+    pub async fn get_homeserver_churn_by_context(
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        context: Path<String>,
+        params: extract::Query<DayRangeParams>,
+    ) -> Result<Json<Vec<model::HomeserverChurnByContext>>, (StatusCode, String)> {
+        super::get_homeserver_churn_by_context(db_settings, settings, context, params).await
+    }
+
+    pub async fn get_homeserver_ranking(
+        admin: Admin,
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        params: extract::Query<RankingParams>,
+    ) -> Result<Json<model::Ranking>, (StatusCode, String)> {
+        super::get_homeserver_ranking(admin, db_settings, settings, params).await
+    }
+
+    pub async fn query_homeserver_daily(
+        admin: Admin,
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        params: extract::Query<GroupQueryParams>,
+    ) -> Result<Json<model::QueryResult>, (StatusCode, String)> {
+        super::query_homeserver_daily(admin, db_settings, settings, params).await
+    }
+
+    pub async fn get_homeserver_history(
+        admin: Admin,
+        db_settings: State<Arc<DBSettings>>,
+        settings: extract::Extension<Arc<ServerSettings>>,
+        name: Path<String>,
+        params: extract::Query<DayRangeParams>,
+    ) -> Result<Json<Vec<model::HomeserverDay>>, (StatusCode, String)> {
+        super::get_homeserver_history(admin, db_settings, settings, name, params).await
     }
 
     pub async fn get_aggregated_stats_by_context(
         db_settings: State<Arc<DBSettings>>,
+        aggregation: extract::Extension<Arc<AggregationSettings>>,
+        actor: Option<extract::Extension<Actor>>,
         extractors: Path<(sqlx::types::time::Date, String)>,
         params: extract::Query<QueryParams>,
-    ) -> Result<Json<model::AggregatedStatsByContext>, StatusCode> {
-        super::get_aggregated_stats_by_context(db_settings, extractors, params).await
+        headers: HeaderMap,
+    ) -> Result<Response, StatusCode> {
+        super::get_aggregated_stats_by_context(
+            db_settings,
+            aggregation,
+            actor,
+            extractors,
+            params,
+            headers,
+        )
+        .await
     }
 }
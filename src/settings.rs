@@ -1,10 +1,14 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use config::{Config, Environment, File};
 use rust_telemetry::config::OtelConfig;
 use serde::Deserialize;
 
+use crate::model::{AGGREGATED_METRICS, DailyStrategy};
+
 #[derive(Deserialize, Clone)]
 pub struct DBSettings {
     pub url: String,
@@ -24,23 +28,221 @@ impl std::fmt::Display for DBSettings {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Clone)]
 pub struct ServerSettings {
     pub host: String,
+    /// Address of a separate listener for health, metrics, the admin
+    /// endpoints and on-demand aggregation, which `host` then doesn't serve
+    pub admin_host: Option<String>,
+    /// Serve the aggregated stats on `host` as well while `admin_host` is set
+    pub stats_on_host: bool,
+    /// Maximum number of days which can be requested at once
+    pub max_range_days: i64,
+    /// Export the latest aggregated stats as gauges on `/metrics`, which
+    /// requires `admin_host` as `/metrics` takes no API key
+    pub fleet_metrics: bool,
+    /// Bearer token for the admin endpoints, besides API keys with the `admin`
+    /// scope
+    pub admin_token: Option<String>,
+    /// Serve the aggregated stats without API key
+    pub public_stats: bool,
+    /// Maximum number of groups returned by `/admin/query`
+    pub query_max_groups: i64,
+    /// Statement timeout of `/admin/query` in milliseconds
+    pub query_timeout_ms: u64,
+    /// Serve HTTPS on `host` instead of plain HTTP
+    pub tls: Option<TlsSettings>,
+    /// Rules deciding which pushed reports are stored and aggregated
+    pub ingest_rules: Option<IngestRulesSettings>,
+    /// CORS policies for browsers calling the API from other origins
+    #[serde(default)]
+    pub cors: CorsSettings,
+}
+
+impl Debug for ServerSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerSettings")
+            .field("host", &self.host)
+            .field("admin_host", &self.admin_host)
+            .field("stats_on_host", &self.stats_on_host)
+            .field("max_range_days", &self.max_range_days)
+            .field("fleet_metrics", &self.fleet_metrics)
+            .field("public_stats", &self.public_stats)
+            .field("query_max_groups", &self.query_max_groups)
+            .field("query_timeout_ms", &self.query_timeout_ms)
+            .field("tls", &self.tls)
+            .field("ingest_rules", &self.ingest_rules)
+            .field("cors", &self.cors)
+            .field(
+                "admin_token",
+                &self.admin_token.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsSettings {
+    /// PEM file with the certificate chain
+    pub cert_path: PathBuf,
+    /// PEM file with the private key
+    pub key_path: PathBuf,
+    /// Seconds between two checks of the files for changes
+    #[serde(default = "default_tls_reload_seconds")]
+    pub reload_seconds: u64,
+    /// Address of an additional plain HTTP listener
+    pub http_host: Option<String>,
+}
+
+const fn default_tls_reload_seconds() -> u64 {
+    60
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IngestRulesSettings {
+    /// File with the rules, see `ingest-rules.sample.yaml`
+    pub path: PathBuf,
+    /// Seconds between two checks of the file for changes
+    #[serde(default = "default_ingest_rules_reload_seconds")]
+    pub reload_seconds: u64,
+}
+
+const fn default_ingest_rules_reload_seconds() -> u64 {
+    60
+}
+
+/// CORS policy per route group, without one browsers can't call the group
+/// from other origins
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CorsSettings {
+    /// The push endpoint of the homeservers
+    pub push: Option<CorsPolicy>,
+    /// The aggregated stats endpoints
+    pub stats: Option<CorsPolicy>,
+    /// The admin endpoints
+    pub admin: Option<CorsPolicy>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CorsPolicy {
+    /// Origins allowed to call the endpoints, `*` for any
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed in requests, `*` for any
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed in requests, `*` for any
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// Allow requests with credentials, which rules out `*` anywhere
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache the preflight response
+    #[serde(default)]
+    pub max_age_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AggregationSettings {
+    /// Seconds between two aggregation runs
+    pub interval_seconds: u64,
+    /// Only run scheduled jobs on the replica holding the leader lock
+    pub leader_election: bool,
+    /// Key of the PostgreSQL advisory lock used for leader election
+    pub leader_lock_key: i64,
+    /// Seconds between two attempts of a standby replica to become leader
+    pub leader_poll_seconds: u64,
+    /// How the daily value of homeservers with several reports per day is chosen
+    #[serde(default)]
+    pub daily_strategy: DailyStrategy,
+    /// Days without reports after which a homeserver is considered stale
+    pub stale_days: i64,
+    /// Days without reports after which a homeserver is considered churned
+    pub churn_days: i64,
+    /// Seconds after a successful aggregation of a day during which
+    /// `?generate=true` doesn't aggregate it again, 0 to disable
+    pub generate_cooldown_seconds: u64,
+    /// Scoring of incoming reports against the history of their homeserver
+    pub anomaly: AnomalySettings,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnomalySettings {
+    /// Score incoming reports and hold back the anomalous ones for review
+    pub enabled: bool,
+    /// Days before a report which it is compared with
+    pub history_days: i64,
+    /// Days with data within `history_days` needed to score a report
+    pub min_history_days: i64,
+    /// Medians below this are raised to it, so that the usual fluctuation of
+    /// small homeservers isn't flagged
+    pub min_baseline: f64,
+    /// Ratio of a metric to its median above which a report is flagged
+    pub threshold: f64,
+    /// Per metric overrides of `threshold`
+    #[serde(default)]
+    pub thresholds: BTreeMap<String, f64>,
+}
+
+impl AnomalySettings {
+    pub fn threshold_for(&self, metric: &str) -> f64 {
+        self.thresholds
+            .get(metric)
+            .copied()
+            .unwrap_or(self.threshold)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(metric) = self
+            .thresholds
+            .keys()
+            .find(|metric| !AGGREGATED_METRICS.contains(&metric.as_str()))
+        {
+            anyhow::bail!("unknown metric {metric:?} in aggregation.anomaly.thresholds");
+        }
+        anyhow::ensure!(
+            std::iter::once(&self.threshold)
+                .chain(self.thresholds.values())
+                .all(|threshold| *threshold > 1.0),
+            "anomaly thresholds must be greater than 1"
+        );
+        anyhow::ensure!(
+            0 < self.min_history_days && self.min_history_days <= self.history_days,
+            "aggregation.anomaly.min_history_days must be positive and not exceed history_days"
+        );
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Settings {
     pub server: ServerSettings,
     pub database: DBSettings,
+    pub aggregation: AggregationSettings,
     pub telemetry: Option<OtelConfig>,
 }
 
 impl Settings {
     pub fn load(config: &str) -> Result<Self> {
-        Ok(Config::builder()
+        let settings: Self = Config::builder()
             .set_default("server.host", "[::]:8080")?
+            .set_default("server.max_range_days", 366)?
+            .set_default("server.fleet_metrics", false)?
+            .set_default("server.public_stats", false)?
+            .set_default("server.stats_on_host", true)?
+            .set_default("server.query_max_groups", 1000)?
+            .set_default("server.query_timeout_ms", 5000)?
             .set_default("log.level", "info")?
+            .set_default("aggregation.interval_seconds", 3600)?
+            .set_default("aggregation.leader_election", true)?
+            .set_default("aggregation.leader_lock_key", 0x6261_7261_6464_7572_i64)?
+            .set_default("aggregation.leader_poll_seconds", 30)?
+            .set_default("aggregation.stale_days", 2)?
+            .set_default("aggregation.churn_days", 30)?
+            .set_default("aggregation.generate_cooldown_seconds", 300)?
+            .set_default("aggregation.anomaly.enabled", false)?
+            .set_default("aggregation.anomaly.history_days", 28)?
+            .set_default("aggregation.anomaly.min_history_days", 7)?
+            .set_default("aggregation.anomaly.min_baseline", 100.0)?
+            .set_default("aggregation.anomaly.threshold", 10.0)?
             .add_source(File::with_name(config).required(false))
             .add_source(
                 Environment::with_prefix("FAMEDLY_BDR")
@@ -49,6 +251,29 @@ impl Settings {
             )
             .build()
             .context("can't load config")?
-            .try_deserialize()?)
+            .try_deserialize()?;
+
+        settings
+            .aggregation
+            .daily_strategy
+            .validate()
+            .context("invalid aggregation.daily_strategy")?;
+        anyhow::ensure!(
+            0 < settings.aggregation.stale_days
+                && settings.aggregation.stale_days <= settings.aggregation.churn_days,
+            "aggregation.stale_days must be positive and not exceed aggregation.churn_days"
+        );
+        // Otherwise anyone could read the aggregated stats on `/metrics`
+        anyhow::ensure!(
+            !settings.server.fleet_metrics || settings.server.admin_host.is_some(),
+            "server.fleet_metrics requires server.admin_host"
+        );
+        settings
+            .aggregation
+            .anomaly
+            .validate()
+            .context("invalid aggregation.anomaly")?;
+
+        Ok(settings)
     }
 }
@@ -2,10 +2,17 @@
 
 use crate::AggregatedStats;
 use crate::database;
+use crate::ingest::IngestRules;
+use crate::leader;
+use crate::leader::LeaderLock;
 use crate::model;
 use crate::model::AggregatedStatsByContext;
+use crate::model::DailyStrategy;
+use crate::model::Extrapolate;
 use crate::server;
-use crate::settings::DBSettings;
+use crate::settings::{
+    AggregationSettings, AnomalySettings, CorsPolicy, CorsSettings, DBSettings, ServerSettings,
+};
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -27,6 +34,31 @@ use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
 use tower::ServiceExt; // for `app.oneshot()`
 
+fn anomaly_settings() -> AnomalySettings {
+    AnomalySettings {
+        enabled: true,
+        history_days: 28,
+        min_history_days: 3,
+        min_baseline: 100.0,
+        threshold: 10.0,
+        thresholds: std::collections::BTreeMap::from([("daily_active_users".to_owned(), 5.0)]),
+    }
+}
+
+fn aggregation_settings() -> Arc<AggregationSettings> {
+    Arc::new(AggregationSettings {
+        interval_seconds: 3600,
+        leader_election: false,
+        leader_lock_key: 0,
+        leader_poll_seconds: 30,
+        daily_strategy: DailyStrategy::default(),
+        stale_days: 2,
+        churn_days: 30,
+        generate_cooldown_seconds: 0,
+        anomaly: anomaly_settings(),
+    })
+}
+
 #[tokio::test]
 #[allow(clippy::too_many_lines)]
 async fn integration_testing() {
@@ -50,6 +82,8 @@ async fn integration_testing() {
         )
         .with_state(Arc::new(db_settings.clone()))
         .layer(Extension(tx.clone()))
+        .layer(Extension(Arc::new(IngestRules::default())))
+        .layer(Extension(aggregation_settings()))
         .layer(MockConnectInfo(SocketAddr::from(([0, 0, 0, 0], 1337))));
 
     let mut test_payloads = HashMap::new();
@@ -110,12 +144,9 @@ async fn integration_testing() {
         );
     }
     let today = time::OffsetDateTime::now_utc().date();
-    database::aggregate_stats(&db_settings, today)
-        .await
-        .expect("aggregate stats");
-    database::aggregate_stats_by_context(&db_settings, today)
+    database::aggregate_day(&db_settings, &DailyStrategy::default(), today)
         .await
-        .expect("aggregate stats by context");
+        .expect("aggregate day");
 
     let date = time::OffsetDateTime::now_local()
         .unwrap_or_else(|_| time::OffsetDateTime::now_utc())
@@ -212,6 +243,8 @@ async fn load_test() {
         )
         .with_state(Arc::new(db_settings.clone()))
         .layer(Extension(tx.clone()))
+        .layer(Extension(Arc::new(IngestRules::default())))
+        .layer(Extension(aggregation_settings()))
         .layer(MockConnectInfo(SocketAddr::from(([0, 0, 0, 0], 1337))));
 
     let mut days = Vec::new();
@@ -294,16 +327,22 @@ async fn load_test() {
         set.spawn(async move {
             let _permit = permit;
             //println!("Aggregating stats for day: {}", day.date());
-            database::aggregate_stats(&db_settings, day.date())
+            database::aggregate_day(&db_settings, &DailyStrategy::default(), day.date())
                 .await
-                .expect("aggregate stats");
-            database::aggregate_stats_by_context(&db_settings, day.date())
-                .await
-                .expect("aggregate stats by context");
+                .expect("aggregate day");
         });
     }
     set.join_all().await;
 
+    // Running totals count the days of other tests before these too
+    let first = database::get_aggregated_stats(&db_settings, days[0].date())
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    let earlier_messages = first.total_messages.unwrap() - DAILY_MESSAGES * HOMESERVERS;
+    let earlier_e2ee_messages =
+        first.total_e2ee_messages.unwrap() - DAILY_E2EE_MESSAGES * HOMESERVERS;
+
     let mut set = JoinSet::new();
     let sem = Arc::new(Semaphore::new(20));
 
@@ -354,11 +393,11 @@ async fn load_test() {
             );
             assert_eq!(
                 body.total_messages.unwrap(),
-                (DAILY_MESSAGES * HOMESERVERS) * (day_pos + 1)
+                earlier_messages + (DAILY_MESSAGES * HOMESERVERS) * (day_pos + 1)
             );
             assert_eq!(
                 body.total_e2ee_messages.unwrap(),
-                (DAILY_E2EE_MESSAGES * HOMESERVERS) * (day_pos + 1)
+                earlier_e2ee_messages + (DAILY_E2EE_MESSAGES * HOMESERVERS) * (day_pos + 1)
             );
         });
         for homeserver in 0..HOMESERVERS.min(2) {
@@ -439,6 +478,8 @@ async fn get_aggregate_state_with_generate_test() {
         )
         .with_state(Arc::new(db_settings.clone()))
         .layer(Extension(tx.clone()))
+        .layer(Extension(Arc::new(IngestRules::default())))
+        .layer(Extension(aggregation_settings()))
         .layer(MockConnectInfo(SocketAddr::from(([0, 0, 0, 0], 1337))));
 
     let mut days = Vec::new();
@@ -616,3 +657,2888 @@ async fn test_healthcheck_no_db() {
         resp.body(),
     );
 }
+
+#[tokio::test]
+async fn test_leader_election() {
+    let db_url = env::var("DATABASE_URL").expect("database URL");
+    let key = 0x6c65_6164_6572_i64;
+
+    let mut leader = LeaderLock::new(&db_url, key);
+    let mut standby = LeaderLock::new(&db_url, key);
+
+    assert!(leader.ensure().await, "first replica acquires the lock");
+    assert!(!standby.ensure().await, "second replica stays on standby");
+    assert!(leader.ensure().await, "leader keeps the lock");
+
+    leader::tests::release(&mut leader)
+        .await
+        .expect("release leader lock");
+    assert!(standby.ensure().await, "standby takes over after release");
+    leader::tests::release(&mut standby)
+        .await
+        .expect("release leader lock");
+}
+
+#[tokio::test]
+async fn test_reaggregation_keeps_r30v2() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(2001, time::Month::September, 1).unwrap();
+    let report: model::Report = serde_json::from_value(json!({
+        "homeserver": "r30v2_test",
+        "server_context": "r30v2_test",
+        "r30_users_all": 3,
+        "r30v2_users_all": 7,
+        "local_timestamp": (day.midnight().assume_utc() + Duration::hours(12)).unix_timestamp(),
+    }))
+    .expect("report");
+    database::tests::save_report(&pool, &report)
+        .await
+        .expect("save report");
+
+    // The second run updates the rows written by the first one
+    for _ in 0..2 {
+        database::aggregate_day(&db_settings, &DailyStrategy::default(), day)
+            .await
+            .expect("aggregate day");
+    }
+
+    let stats = database::get_aggregated_stats(&db_settings, day)
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    assert_eq!(stats.r30_users_all, Some(3));
+    assert_eq!(stats.r30v2_users_all, Some(7));
+
+    let stats =
+        database::get_aggregated_stats_by_context(&db_settings, day, "r30v2_test".to_owned())
+            .await
+            .expect("get aggregated stats by context")
+            .expect("aggregated stats by context");
+    assert_eq!(stats.r30_users_all, Some(3));
+    assert_eq!(stats.r30v2_users_all, Some(7));
+}
+
+#[tokio::test]
+async fn test_homeserver_daily_selection() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(2001, time::Month::February, 3).unwrap();
+    let midnight = day.midnight().assume_utc();
+    // Left over by earlier runs of this test
+    for table in ["reports", "homeserver_daily"] {
+        sqlx::query(&format!(
+            "DELETE FROM {table} WHERE homeserver LIKE 'daily_test%'"
+        ))
+        .execute(&pool)
+        .await
+        .expect("clean up");
+    }
+    sqlx::query("DELETE FROM aggregation_runs WHERE day = $1")
+        .bind(day)
+        .execute(&pool)
+        .await
+        .expect("delete aggregation runs");
+
+    for (homeserver, hour, daily_active_users) in [
+        ("daily_test_0", 1, 10),
+        ("daily_test_0", 20, 30),
+        ("daily_test_0", 8, 20),
+        ("daily_test_1", 12, 5),
+    ] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": "daily_test",
+            "daily_active_users": daily_active_users,
+            "local_timestamp": (midnight + Duration::hours(hour)).unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+
+    database::aggregate_day(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("aggregate day");
+
+    let stats = database::get_aggregated_stats(&db_settings, day)
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    assert_eq!(stats.daily_active_users, Some(35));
+    assert_eq!(stats.daily_active_homeservers, Some(2));
+    assert_eq!(stats.report_count, Some(4));
+    assert!(stats.computed_at.is_some());
+
+    let runs: Vec<(String, String, Option<i64>)> = sqlx::query_as(
+        "SELECT scope, status, report_count FROM aggregation_runs WHERE day = $1 ORDER BY id",
+    )
+    .bind(day)
+    .fetch_all(&pool)
+    .await
+    .expect("aggregation runs");
+    assert_eq!(
+        runs,
+        vec![
+            ("snapshot".to_owned(), "succeeded".to_owned(), Some(4)),
+            ("global".to_owned(), "succeeded".to_owned(), Some(4)),
+            ("context".to_owned(), "succeeded".to_owned(), Some(4)),
+        ]
+    );
+
+    let stats =
+        database::get_aggregated_stats_by_context(&db_settings, day, "daily_test".to_owned())
+            .await
+            .expect("get aggregated stats by context")
+            .expect("aggregated stats by context");
+    assert_eq!(stats.daily_active_users, Some(35));
+    assert_eq!(stats.daily_active_homeservers, Some(2));
+}
+
+#[tokio::test]
+async fn test_aggregate_empty_day() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(1999, time::Month::January, 1).unwrap();
+
+    // A day without reports still makes a successful run
+    database::aggregate_day(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("aggregate empty day");
+
+    let runs: Vec<(String, Option<i64>)> = sqlx::query_as(
+        "SELECT status, report_count FROM aggregation_runs WHERE day = $1 ORDER BY id DESC LIMIT 2",
+    )
+    .bind(day)
+    .fetch_all(&pool)
+    .await
+    .expect("aggregation runs");
+    assert_eq!(
+        runs,
+        vec![
+            ("succeeded".to_owned(), Some(0)),
+            ("succeeded".to_owned(), Some(0)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_daily_strategies() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(2001, time::Month::February, 4).unwrap();
+    let midnight = day.midnight().assume_utc();
+
+    for (hour, daily_active_users, daily_messages, total_users) in
+        [(1, 10, 3, 100), (8, 20, 6, 200), (20, 15, 12, 300)]
+    {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": "strategy_test",
+            "server_context": "strategy_test",
+            "daily_active_users": daily_active_users,
+            "daily_messages": daily_messages,
+            "total_users": total_users,
+            "local_timestamp": (midnight + Duration::hours(hour)).unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+
+    let strategy: DailyStrategy = serde_json::from_value(json!({
+        "default": "max",
+        "metrics": {
+            "daily_messages": "mean",
+            "total_users": "first_after_cutoff",
+        },
+        "cutoff": "06:00",
+    }))
+    .expect("strategy");
+    strategy.validate().expect("valid strategy");
+
+    database::refresh_snapshot(&db_settings, &strategy, day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats_by_context(&db_settings, day)
+        .await
+        .expect("aggregate stats by context");
+
+    let stats =
+        database::get_aggregated_stats_by_context(&db_settings, day, "strategy_test".to_owned())
+            .await
+            .expect("get aggregated stats by context")
+            .expect("aggregated stats by context");
+    assert_eq!(stats.daily_active_users, Some(20));
+    assert_eq!(stats.daily_messages, Some(7));
+    assert_eq!(stats.total_users, Some(200));
+    assert_eq!(
+        stats.daily_strategy,
+        Some(serde_json::to_value(&strategy).unwrap())
+    );
+
+    let invalid: DailyStrategy =
+        serde_json::from_value(json!({ "default": "first_after_cutoff" })).expect("strategy");
+    assert!(invalid.validate().is_err());
+}
+
+#[tokio::test]
+async fn test_coverage_and_extrapolation() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let (tx, _rx) = mpsc::channel::<model::Report>(1);
+    let day = time::Date::from_calendar_date(2001, time::Month::February, 5).unwrap();
+    let midnight = day.midnight().assume_utc();
+
+    for homeserver in 0..3 {
+        let mut report = json!({
+            "homeserver": format!("coverage_test_{homeserver}"),
+            "server_context": "coverage_test",
+            "daily_active_users": 5,
+            "local_timestamp": (midnight + Duration::hours(12)).unix_timestamp(),
+        });
+        if homeserver == 0 {
+            report["daily_active_e2ee_rooms"] = json!(10);
+        }
+        let report: model::Report = serde_json::from_value(report).expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats_by_context(&db_settings, day)
+        .await
+        .expect("aggregate stats by context");
+
+    let app = Router::new()
+        .route(
+            "/aggregated-stats/{day}/{context}",
+            get(server::tests::get_aggregated_stats_by_context),
+        )
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(tx.clone()))
+        .layer(Extension(aggregation_settings()));
+
+    for (query, daily_active_e2ee_rooms) in [("", 10), ("?extrapolate=true", 30)] {
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri(format!("/aggregated-stats/{day}/coverage_test{query}"))
+                    .body(Body::empty())
+                    .expect("build request"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body: AggregatedStatsByContext =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.expect("body"))
+                .expect("Converting response body to json");
+        let coverage = body.coverage.expect("coverage");
+        assert_eq!(coverage["daily_active_users"], json!(3));
+        assert_eq!(coverage["daily_active_e2ee_rooms"], json!(1));
+        assert_eq!(body.daily_active_users, Some(15));
+        assert_eq!(body.daily_active_e2ee_rooms, Some(daily_active_e2ee_rooms));
+    }
+}
+
+#[test]
+fn test_extrapolate_all_metrics() {
+    let mut stats = json!({
+        "day": "2001-02-05",
+        "daily_active_homeservers": 4,
+        "total_messages": 7,
+        "coverage": {},
+    });
+    for metric in model::AGGREGATED_METRICS {
+        stats[*metric] = json!(3);
+        stats["coverage"][*metric] = json!(2);
+    }
+    let mut stats: AggregatedStats = serde_json::from_value(stats).expect("stats");
+    stats.extrapolate();
+
+    let stats = serde_json::to_value(stats).expect("stats");
+    for metric in model::AGGREGATED_METRICS {
+        assert_eq!(stats[*metric], json!(6), "extrapolating {metric}");
+    }
+    // Running totals aren't summed over homeservers
+    assert_eq!(stats["total_messages"], json!(7));
+}
+
+fn server_settings() -> Arc<ServerSettings> {
+    Arc::new(ServerSettings {
+        host: "[::]:8080".to_owned(),
+        admin_host: None,
+        stats_on_host: true,
+        max_range_days: 31,
+        fleet_metrics: true,
+        public_stats: false,
+        admin_token: Some("admin_token".to_owned()),
+        query_max_groups: 2,
+        query_timeout_ms: 5000,
+        tls: None,
+        ingest_rules: None,
+        cors: CorsSettings::default(),
+    })
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_aggregated_stats_range() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let first = time::Date::from_calendar_date(2001, time::Month::March, 1).unwrap();
+    let last = time::Date::from_calendar_date(2001, time::Month::March, 3).unwrap();
+
+    for day in [first, last] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": "range_test",
+            "server_context": "range_test",
+            "daily_active_users": 7,
+            "total_users": 12,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+        database::aggregate_day(&db_settings, &DailyStrategy::default(), day)
+            .await
+            .expect("aggregate day");
+    }
+
+    let app = Router::new()
+        .route(
+            "/aggregated-stats",
+            get(server::tests::get_aggregated_stats_range),
+        )
+        .route(
+            "/aggregated-stats-by-context/{context}",
+            get(server::tests::get_aggregated_stats_by_context_range),
+        )
+        .route(
+            "/aggregated-stats/{day}/{context}",
+            get(server::tests::get_aggregated_stats_by_context),
+        )
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(server_settings()))
+        .layer(Extension(aggregation_settings()));
+
+    let get_as = |uri: String, accept: &'static str| {
+        let app = app.clone();
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(&uri)
+                        .header(http::header::ACCEPT, accept)
+                        .body(Body::empty())
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            let status = res.status();
+            let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+            (status, body)
+        }
+    };
+    let get = |uri: String| get_as(uri, "*/*");
+
+    for uri in [
+        format!("/aggregated-stats?from={first}&to={last}&fields=daily_active_users"),
+        format!(
+            "/aggregated-stats-by-context/range_test?from={first}&to={last}&fields=daily_active_users"
+        ),
+    ] {
+        let (status, body) = get(uri.clone()).await;
+        assert_eq!(status, StatusCode::OK, "testing GET '{uri}'");
+        let body: model::StatsRange = serde_json::from_slice(&body).expect("stats range");
+        assert_eq!(body.stats.len(), 2);
+        assert_eq!(body.stats[0]["day"], json!(first));
+        assert_eq!(body.stats[0]["daily_active_users"], json!(7));
+        assert!(body.stats[0].get("total_users").is_none());
+        assert_eq!(body.stats[1]["day"], json!(last));
+        assert_eq!(body.missing_days, vec![first.next_day().unwrap()]);
+    }
+
+    for uri in [
+        format!("/aggregated-stats?from={last}&to={first}"),
+        format!("/aggregated-stats?from={first}&to=2001-06-01"),
+        format!("/aggregated-stats?from={first}&to={last}&fields=unknown"),
+    ] {
+        let (status, _) = get(uri.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "testing GET '{uri}'");
+    }
+
+    // CSV, either through the `Accept` header or the `format` parameter
+    let (status, body) = get_as(
+        format!(
+            "/aggregated-stats-by-context/range_test?from={first}&to={last}&fields=total_users,daily_active_users"
+        ),
+        "application/json;q=0.5, text/csv",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        String::from_utf8(body.to_vec()).expect("utf-8"),
+        format!(
+            "day,server_context,total_users,daily_active_users\n{first},range_test,12,7\n{last},range_test,12,7\n"
+        )
+    );
+
+    let (status, body) = get(format!("/aggregated-stats/{first}/range_test?format=csv")).await;
+    assert_eq!(status, StatusCode::OK);
+    let body = String::from_utf8(body.to_vec()).expect("utf-8");
+    let mut lines = body.lines();
+    let columns = lines.next().expect("header").split(',').collect::<Vec<_>>();
+    assert_eq!(columns, model::field_names::<AggregatedStatsByContext>());
+    assert_eq!(lines.count(), 1);
+}
+
+#[tokio::test]
+async fn test_csv_formulas() {
+    let rows = [
+        "=HYPERLINK(\"https://example.com\")",
+        "+1",
+        "-1",
+        "@SUM(A1)",
+        "\tcmd",
+        "acme",
+    ]
+    .into_iter()
+    .map(|server_context| {
+        let row = json!({ "server_context": server_context, "daily_messages": -3 });
+        row.as_object().expect("row").clone()
+    })
+    .collect();
+    let res = crate::export::csv_response(vec!["server_context", "daily_messages"], rows);
+    let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+
+    // Text which spreadsheets would evaluate is prefixed, numbers are kept
+    assert_eq!(
+        String::from_utf8(body.to_vec()).expect("utf-8"),
+        "server_context,daily_messages\n\
+         \"'=HYPERLINK(\"\"https://example.com\"\")\",-3\n\
+         '+1,-3\n\
+         '-1,-3\n\
+         '@SUM(A1),-3\n\
+         '\tcmd,-3\n\
+         acme,-3\n"
+    );
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    // Far in the future, so that it is the latest aggregated day
+    let day = time::Date::from_calendar_date(2900, time::Month::January, 1).unwrap();
+    let report: model::Report = serde_json::from_value(json!({
+        "homeserver": "metrics_test",
+        "server_context": "metrics_test",
+        "daily_active_users": 3,
+        "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+    }))
+    .expect("report");
+    database::tests::save_report(&pool, &report)
+        .await
+        .expect("save report");
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats_by_context(&db_settings, day)
+        .await
+        .expect("aggregate stats by context");
+
+    let (tx, _rx) = mpsc::channel::<model::Report>(64);
+    let app = Router::new()
+        .route("/report-usage-stats/push", put(server::tests::save_report))
+        .route("/metrics", get(server::tests::get_metrics))
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(tx))
+        .layer(Extension(Arc::new(IngestRules::default())))
+        .layer(Extension(server_settings()))
+        .layer(MockConnectInfo(SocketAddr::from(([0, 0, 0, 0], 1337))));
+
+    for (payload, status) in [
+        (r#"{"homeserver": "metrics_test"}"#, StatusCode::OK),
+        (r#"{"homeserver": 42}"#, StatusCode::UNPROCESSABLE_ENTITY),
+    ] {
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::PUT)
+                    .uri("/report-usage-stats/push")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(payload))
+                    .expect("building request"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), status, "pushing {payload}");
+    }
+
+    let resp = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .expect("building request"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = String::from_utf8(
+        to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .expect("body")
+            .to_vec(),
+    )
+    .expect("utf-8");
+
+    for expected in [
+        "barad_dur_reports_received_total ",
+        "barad_dur_reports_rejected_total{reason=\"invalid\"} ",
+        "barad_dur_report_channel_depth 1",
+        "barad_dur_aggregation_duration_seconds_count{scope=\"context\",status=\"succeeded\"} ",
+        "barad_dur_aggregation_last_success_timestamp_seconds{scope=\"context\"} ",
+        "barad_dur_leader ",
+        "barad_dur_aggregated_daily_active_users{server_context=\"metrics_test\"} 3",
+    ] {
+        assert!(body.contains(expected), "{expected:?} missing in {body}");
+    }
+}
+
+#[tokio::test]
+async fn test_contexts() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let first = time::Date::from_calendar_date(2001, time::Month::May, 1).unwrap();
+    let last = time::Date::from_calendar_date(2001, time::Month::May, 2).unwrap();
+
+    for (homeserver, day, total_users) in [
+        ("contexts_test_1", first, 10),
+        ("contexts_test_1", last, 20),
+        ("contexts_test_2", last, 5),
+    ] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": "contexts_test",
+            "total_users": total_users,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    for day in [first, last] {
+        database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+            .await
+            .expect("refresh snapshot");
+        database::aggregate_stats_by_context(&db_settings, day)
+            .await
+            .expect("aggregate stats by context");
+    }
+
+    let app = Router::new()
+        .route("/contexts", get(server::tests::get_contexts))
+        .with_state(Arc::new(db_settings.clone()));
+    let get = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .body(Body::empty())
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            let status = res.status();
+            let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+            (status, body)
+        }
+    };
+
+    let (status, body) = get("/contexts?limit=1000").await;
+    assert_eq!(status, StatusCode::OK);
+    let page: model::Page<model::ContextSummary> = serde_json::from_slice(&body).expect("page");
+    assert_eq!(page.limit, 1000);
+    assert_eq!(page.offset, 0);
+    assert!(page.total >= 1);
+    let context = page
+        .items
+        .iter()
+        .find(|context| context.server_context == "contexts_test")
+        .expect("context listed");
+    assert_eq!(
+        context,
+        &model::ContextSummary {
+            server_context: "contexts_test".to_owned(),
+            first_day: first,
+            last_day: last,
+            homeservers: Some(2),
+            total_users: Some(25),
+        }
+    );
+
+    let (status, body) = get("/contexts?limit=1").await;
+    assert_eq!(status, StatusCode::OK);
+    let page: model::Page<model::ContextSummary> = serde_json::from_slice(&body).expect("page");
+    assert_eq!(page.items.len(), 1);
+
+    for uri in ["/contexts?limit=0", "/contexts?offset=-1"] {
+        let (status, _) = get(uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "testing GET '{uri}'");
+    }
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_homeserver_directory() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let now = time::OffsetDateTime::now_utc();
+    // Left over by earlier runs of this test
+    sqlx::query("DELETE FROM reports WHERE homeserver LIKE 'directory_test%'")
+        .execute(&pool)
+        .await
+        .expect("clean up reports");
+
+    for (homeserver, age, version) in [
+        ("directory_test_active", 0, "1.99.0"),
+        ("directory_test_active", 3, "1.98.0"),
+        ("directory_test_stale", 5, "1.98.0"),
+        ("directory_test_churned", 60, "1.28.0"),
+    ] {
+        let mut report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": "directory_test",
+            "total_users": 10 + age,
+            "local_timestamp": (now - Duration::days(age)).unix_timestamp(),
+        }))
+        .expect("report");
+        report.user_agent = Some(format!("Synapse/{version}"));
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+
+    let settings = server_settings();
+    let app = Router::new()
+        .route("/admin/homeservers", get(server::tests::get_homeservers))
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(aggregation_settings()));
+    let get = |uri: &'static str, token: Option<&'static str>, settings: Arc<ServerSettings>| {
+        let app = app.clone().layer(Extension(settings));
+        async move {
+            let mut request = Request::builder().uri(uri);
+            if let Some(token) = token {
+                request = request.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let res = app
+                .oneshot(request.body(Body::empty()).expect("build request"))
+                .await
+                .unwrap();
+            let status = res.status();
+            let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+            (status, body)
+        }
+    };
+
+    let (status, body) = get(
+        "/admin/homeservers?server_context=directory_test&sort=last_report&order=desc",
+        Some("admin_token"),
+        settings.clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let page: model::Page<model::HomeserverSummary> = serde_json::from_slice(&body).expect("page");
+    assert_eq!(page.total, 3);
+    let homeservers = page
+        .items
+        .iter()
+        .map(|homeserver| {
+            (
+                homeserver.homeserver.as_str(),
+                homeserver.status,
+                homeserver.version.as_deref(),
+                homeserver.report_count,
+                homeserver.total_users,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        homeservers,
+        vec![
+            (
+                "directory_test_active",
+                model::HomeserverStatus::Active,
+                Some("1.99.0"),
+                2,
+                Some(10)
+            ),
+            (
+                "directory_test_stale",
+                model::HomeserverStatus::Stale,
+                Some("1.98.0"),
+                1,
+                Some(15)
+            ),
+            (
+                "directory_test_churned",
+                model::HomeserverStatus::Churned,
+                Some("1.28.0"),
+                1,
+                Some(70)
+            ),
+        ]
+    );
+
+    let (status, body) = get(
+        "/admin/homeservers?search=DIRECTORY_TEST&status=stale",
+        Some("admin_token"),
+        settings.clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let page: model::Page<model::HomeserverSummary> = serde_json::from_slice(&body).expect("page");
+    assert_eq!(page.total, 1);
+    assert_eq!(page.items[0].homeserver, "directory_test_stale");
+
+    let (status, _) = get(
+        "/admin/homeservers?sort=homeserver%3B%20DROP%20TABLE%20reports",
+        Some("admin_token"),
+        settings.clone(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    for token in [None, Some("wrong")] {
+        let (status, _) = get("/admin/homeservers", token, settings.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // Without `admin_token`, it is just another unknown bearer token
+    let without_admin_token = Arc::new(ServerSettings {
+        admin_token: None,
+        ..(*settings).clone()
+    });
+    let (status, _) = get(
+        "/admin/homeservers",
+        Some("admin_token"),
+        without_admin_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_homeserver_history() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let aggregated = time::Date::from_calendar_date(2001, time::Month::June, 1).unwrap();
+    let pending = aggregated.next_day().unwrap();
+    // Left over by earlier runs of this test
+    for table in ["reports", "homeserver_daily"] {
+        sqlx::query(&format!(
+            "DELETE FROM {table} WHERE homeserver = 'history_test'"
+        ))
+        .execute(&pool)
+        .await
+        .expect("clean up");
+    }
+
+    for (timestamp, daily_active_users) in [
+        (aggregated.with_hms(8, 0, 0).unwrap(), 5),
+        (aggregated.with_hms(20, 0, 0).unwrap(), 8),
+        (pending.with_hms(8, 0, 0).unwrap(), 9),
+    ] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": "history_test",
+            "daily_active_users": daily_active_users,
+            "local_timestamp": timestamp.assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), aggregated)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats(&db_settings, aggregated)
+        .await
+        .expect("aggregate stats");
+
+    let app = Router::new()
+        .route(
+            "/admin/homeservers/{name}/history",
+            get(server::tests::get_homeserver_history),
+        )
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(server_settings()));
+    let request = |token: &str| {
+        Request::builder()
+            .uri("/admin/homeservers/history_test/history?from=2001-05-31&to=2001-06-03")
+            .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .expect("build request")
+    };
+
+    let res = app.clone().oneshot(request("admin_token")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let history: Vec<model::HomeserverDay> =
+        serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.expect("body"))
+            .expect("history");
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].day, aggregated);
+    assert_eq!(history[0].report_count, 2);
+    assert!(history[0].report_id.is_some());
+    assert_eq!(history[0].daily_active_users, Some(8));
+    assert_eq!(history[1].day, pending);
+    assert_eq!(history[1].report_count, 1);
+    assert_eq!(history[1].report_id, None);
+    assert_eq!(history[1].daily_active_users, None);
+
+    let res = app.oneshot(request("wrong")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_homeserver_churn() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = |day| time::Date::from_calendar_date(2002, time::Month::January, day).unwrap();
+
+    // churn_test_a churns after the 2nd and returns on the 10th
+    for (homeserver, active) in [
+        ("churn_test_a", day(1)),
+        ("churn_test_a", day(2)),
+        ("churn_test_b", day(2)),
+        ("churn_test_a", day(10)),
+    ] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": "churn_test",
+            "local_timestamp": active.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    for day in (1..=10).map(day) {
+        database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+            .await
+            .expect("refresh snapshot");
+        database::aggregate_churn(&db_settings, 3, day)
+            .await
+            .expect("aggregate churn");
+    }
+
+    let app = Router::new()
+        .route("/churn", get(server::tests::get_homeserver_churn))
+        .route(
+            "/contexts/{context}/churn",
+            get(server::tests::get_homeserver_churn_by_context),
+        )
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(server_settings()));
+    let get = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .body(Body::empty())
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK, "testing GET '{uri}'");
+            to_bytes(res.into_body(), usize::MAX).await.expect("body")
+        }
+    };
+
+    let churn: Vec<model::HomeserverChurn> =
+        serde_json::from_slice(&get("/churn?from=2002-01-01&to=2002-01-10").await).expect("churn");
+    let counts = churn
+        .iter()
+        .map(|churn| {
+            (
+                churn.day.day(),
+                churn.active_homeservers,
+                churn.new_homeservers,
+                churn.returning_homeservers,
+                churn.churned_homeservers,
+            )
+        })
+        .filter(|(_, active, new, returning, churned)| active + new + returning + churned > 0)
+        .collect::<Vec<_>>();
+    assert_eq!(churn.len(), 10);
+    assert_eq!(
+        counts,
+        vec![
+            (1, 1, 1, 0, 0),
+            (2, 2, 1, 0, 0),
+            (5, 0, 0, 0, 2),
+            (10, 1, 0, 1, 0)
+        ]
+    );
+
+    let churn: Vec<model::HomeserverChurnByContext> = serde_json::from_slice(
+        &get("/contexts/churn_test/churn?from=2002-01-01&to=2002-01-10").await,
+    )
+    .expect("churn by context");
+    let churned = churn
+        .iter()
+        .map(|churn| {
+            (
+                churn.day.day(),
+                churn.churned_homeservers,
+                churn.returning_homeservers,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        churned,
+        vec![(1, 0, 0), (2, 0, 0), (4, 0, 0), (5, 2, 0), (10, 0, 1)]
+    );
+}
+
+#[tokio::test]
+#[allow(clippy::float_cmp, clippy::too_many_lines)]
+async fn test_homeserver_ranking() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let first = time::Date::from_calendar_date(2001, time::Month::July, 1).unwrap();
+    let second = first.next_day().unwrap();
+
+    for (homeserver, day, daily_active_users) in [
+        ("ranking_test_a", first, 10),
+        ("ranking_test_b", first, 30),
+        ("ranking_test_c", first, 60),
+        ("ranking_test_a", second, 80),
+    ] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": "ranking_test",
+            "daily_active_users": daily_active_users,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    for day in [first, second] {
+        database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+            .await
+            .expect("refresh snapshot");
+        database::aggregate_stats_by_context(&db_settings, day)
+            .await
+            .expect("aggregate stats by context");
+    }
+
+    let app = Router::new()
+        .route(
+            "/admin/rankings",
+            get(server::tests::get_homeserver_ranking),
+        )
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(server_settings()));
+    let get = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .header(http::header::AUTHORIZATION, "Bearer admin_token")
+                        .body(Body::empty())
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            let status = res.status();
+            let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+            (status, body)
+        }
+    };
+
+    let (status, body) = get(
+        "/admin/rankings?metric=daily_active_users&from=2001-07-01&server_context=ranking_test&limit=2",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let ranking: model::Ranking = serde_json::from_slice(&body).expect("ranking");
+    let aggregated =
+        database::get_aggregated_stats_by_context(&db_settings, first, "ranking_test".to_owned())
+            .await
+            .expect("aggregated stats")
+            .expect("aggregated stats of the day");
+    // The total reconciles with the aggregated stats
+    assert_eq!(
+        ranking.total,
+        aggregated.daily_active_users.map(|dau| dau as f64)
+    );
+    assert_eq!(
+        ranking.homeservers,
+        vec![
+            model::RankedHomeserver {
+                rank: 1,
+                homeserver: "ranking_test_c".to_owned(),
+                value: 60.0,
+                share: Some(0.6),
+            },
+            model::RankedHomeserver {
+                rank: 2,
+                homeserver: "ranking_test_b".to_owned(),
+                value: 30.0,
+                share: Some(0.3),
+            },
+        ]
+    );
+
+    let (status, body) = get(
+        "/admin/rankings?metric=daily_active_users&function=max&from=2001-07-01&to=2001-07-02&server_context=ranking_test",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let ranking: model::Ranking = serde_json::from_slice(&body).expect("ranking");
+    assert_eq!(ranking.homeservers.len(), 3);
+    assert_eq!(ranking.homeservers[0].homeserver, "ranking_test_a");
+    assert_eq!(ranking.homeservers[0].value, 80.0);
+
+    for uri in [
+        "/admin/rankings?metric=homeserver&from=2001-07-01",
+        "/admin/rankings?metric=daily_active_users&function=median&from=2001-07-01",
+        "/admin/rankings?metric=daily_active_users&from=2001-07-01&limit=0",
+    ] {
+        let (status, _) = get(uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "testing GET '{uri}'");
+    }
+}
+
+#[tokio::test]
+async fn test_group_query() {
+    let db_settings = DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(2001, time::Month::August, 1).unwrap();
+
+    for (homeserver, database_engine, version, daily_active_users) in [
+        ("query_test_a", Some("psycopg2"), "1.99.0", 10),
+        ("query_test_b", Some("psycopg2"), "1.98.0", 20),
+        ("query_test_c", Some("sqlite3"), "1.99.0", 5),
+        ("query_test_d", None, "1.99.0", 1),
+    ] {
+        let mut report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": "query_test",
+            "database_engine": database_engine,
+            "daily_active_users": daily_active_users,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        report.user_agent = Some(format!("Synapse/{version}"));
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats_by_context(&db_settings, day)
+        .await
+        .expect("aggregate stats by context");
+
+    let app = Router::new()
+        .route("/admin/query", get(server::tests::query_homeserver_daily))
+        .with_state(Arc::new(db_settings.clone()))
+        .layer(Extension(server_settings()));
+    let get = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .header(http::header::AUTHORIZATION, "Bearer admin_token")
+                        .body(Body::empty())
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            let status = res.status();
+            let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+            (status, body)
+        }
+    };
+    let groups = |result: &model::QueryResult| {
+        result
+            .groups
+            .iter()
+            .map(|group| (group.key.clone(), group.value, group.homeservers))
+            .collect::<Vec<_>>()
+    };
+
+    // Three database engines, but at most two groups
+    let (status, body) = get(
+        "/admin/query?dimension=database_engine&metric=daily_active_users&from=2001-08-01&to=2001-08-01&server_context=query_test",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let result: model::QueryResult = serde_json::from_slice(&body).expect("query result");
+    assert!(result.truncated);
+    assert_eq!(
+        groups(&result),
+        vec![
+            (Some("psycopg2".to_owned()), Some(30.0), 2),
+            (Some("sqlite3".to_owned()), Some(5.0), 1),
+        ]
+    );
+
+    let (status, body) = get(
+        "/admin/query?dimension=synapse_version&metric=daily_active_users&function=count&from=2001-08-01&to=2001-08-01&server_context=query_test",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let result: model::QueryResult = serde_json::from_slice(&body).expect("query result");
+    assert!(!result.truncated);
+    assert_eq!(
+        groups(&result),
+        vec![
+            (Some("1.99.0".to_owned()), Some(3.0), 3),
+            (Some("1.98.0".to_owned()), Some(1.0), 1),
+        ]
+    );
+
+    for uri in [
+        "/admin/query?dimension=homeserver&metric=daily_active_users&from=2001-08-01&to=2001-08-01",
+        "/admin/query?dimension=log_level&metric=1;DROP&from=2001-08-01&to=2001-08-01",
+    ] {
+        let (status, _) = get(uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "testing GET '{uri}'");
+    }
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_api_key_management() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let app = server::tests::router(&db_settings)
+        .with_state(db_settings.clone())
+        .layer(Extension(server_settings()));
+    let request =
+        |method: http::Method, uri: String, token: &str, body: Option<serde_json::Value>| {
+            let app = app.clone();
+            let token = token.to_owned();
+            async move {
+                let request = Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+                let request = match body {
+                    Some(body) => request
+                        .header(http::header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(body.to_string())),
+                    None => request.body(Body::empty()),
+                };
+                let res = app.oneshot(request.expect("build request")).await.unwrap();
+                let status = res.status();
+                let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+                (status, body)
+            }
+        };
+    let contexts = |token: String| request(http::Method::GET, "/contexts".to_owned(), &token, None);
+
+    let (status, body) = request(
+        http::Method::POST,
+        "/admin/keys".to_owned(),
+        "admin_token",
+        Some(json!({ "label": "key_management_test", "scopes": ["stats:global"] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let created: model::ApiKeySecret = serde_json::from_slice(&body).expect("API key");
+    assert!(created.secret.starts_with("bdr_"));
+    assert_eq!(created.key.scopes, vec!["stats:global".to_owned()]);
+    assert_eq!(created.key.last_used_at, None);
+    let id = created.key.id;
+
+    assert_eq!(contexts(created.secret.clone()).await.0, StatusCode::OK);
+    // Other keys can't manage keys
+    let (status, _) = request(
+        http::Method::GET,
+        "/admin/keys".to_owned(),
+        &created.secret,
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, body) = request(
+        http::Method::GET,
+        "/admin/keys?limit=1000".to_owned(),
+        "admin_token",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let keys: model::Page<model::ApiKey> = serde_json::from_slice(&body).expect("API keys");
+    let key = keys
+        .items
+        .iter()
+        .find(|key| key.id == id)
+        .expect("created key");
+    assert_eq!(key.label, "key_management_test");
+    assert!(key.last_used_at.is_some());
+    // Using it again right away doesn't write the key
+    let last_used_at = key.last_used_at;
+    request(
+        http::Method::GET,
+        "/admin/keys".to_owned(),
+        &created.secret,
+        None,
+    )
+    .await;
+    let (_, body) = request(
+        http::Method::GET,
+        "/admin/keys?limit=1000".to_owned(),
+        "admin_token",
+        None,
+    )
+    .await;
+    let keys: model::Page<model::ApiKey> = serde_json::from_slice(&body).expect("API keys");
+    let key = keys
+        .items
+        .iter()
+        .find(|key| key.id == id)
+        .expect("created key");
+    assert_eq!(key.last_used_at, last_used_at);
+
+    for body in [
+        json!({ "label": "key_management_test", "scopes": ["stats:everything"] }),
+        json!({ "label": "key_management_test", "scopes": [] }),
+        json!({ "label": " ", "scopes": ["admin"] }),
+    ] {
+        let (status, _) = request(
+            http::Method::POST,
+            "/admin/keys".to_owned(),
+            "admin_token",
+            Some(body.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "creating {body}");
+    }
+
+    let (status, body) = request(
+        http::Method::POST,
+        format!("/admin/keys/{id}/rotate"),
+        "admin_token",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let rotated: model::ApiKeySecret = serde_json::from_slice(&body).expect("API key");
+    assert_ne!(rotated.secret, created.secret);
+    assert_eq!(contexts(created.secret).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(contexts(rotated.secret.clone()).await.0, StatusCode::OK);
+
+    for (expires_at, expected) in [
+        (json!("2001-01-01T00:00:00Z"), StatusCode::UNAUTHORIZED),
+        (json!(null), StatusCode::OK),
+    ] {
+        let (status, _) = request(
+            http::Method::PUT,
+            format!("/admin/keys/{id}/expiry"),
+            "admin_token",
+            Some(json!({ "expires_at": expires_at })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(contexts(rotated.secret.clone()).await.0, expected);
+    }
+
+    let (status, body) = request(
+        http::Method::DELETE,
+        format!("/admin/keys/{id}"),
+        "admin_token",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let revoked: model::ApiKey = serde_json::from_slice(&body).expect("API key");
+    assert!(revoked.revoked_at.is_some());
+    assert_eq!(contexts(rotated.secret).await.0, StatusCode::UNAUTHORIZED);
+
+    for (method, uri) in [
+        (http::Method::POST, format!("/admin/keys/{id}/rotate")),
+        (http::Method::DELETE, "/admin/keys/-1".to_owned()),
+    ] {
+        let (status, _) = request(method, uri.clone(), "admin_token", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "testing '{uri}'");
+    }
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_generate_on_demand() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(2003, time::Month::February, 1).unwrap();
+    let other_day = day.next_day().unwrap();
+    for day in [day, other_day] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": "generate_test",
+            "server_context": "generate_test",
+            "daily_active_users": 5,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    // Earlier runs of this test would throttle generation
+    sqlx::query("DELETE FROM aggregation_runs WHERE day IN ($1, $2)")
+        .bind(day)
+        .bind(other_day)
+        .execute(&pool)
+        .await
+        .expect("delete aggregation runs");
+    let key = "generate_test_global";
+    sqlx::query(
+        "INSERT INTO api_keys (label, key_hash, scopes) VALUES ($1, $2, '{stats:global}')
+         ON CONFLICT (key_hash) DO NOTHING",
+    )
+    .bind(key)
+    .bind(crate::auth::hash_key(key))
+    .execute(&pool)
+    .await
+    .expect("insert API key");
+
+    let app = |settings: Arc<ServerSettings>| {
+        server::tests::router(&db_settings)
+            .with_state(db_settings.clone())
+            .layer(Extension(Arc::new(AggregationSettings {
+                generate_cooldown_seconds: 300,
+                ..(*aggregation_settings()).clone()
+            })))
+            .layer(Extension(settings))
+    };
+    let get = |settings: Arc<ServerSettings>, uri: String, token: Option<&'static str>| {
+        let app = app(settings);
+        async move {
+            let mut request = Request::builder().uri(uri);
+            if let Some(token) = token {
+                request = request.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            app.oneshot(request.body(Body::empty()).expect("build request"))
+                .await
+                .unwrap()
+        }
+    };
+    let aggregation_status = |res: &http::Response<Body>| {
+        res.headers()
+            .get("x-aggregation-status")
+            .map(|value| value.to_str().expect("header value").to_owned())
+    };
+
+    let generate = format!("/aggregated-stats/{day}?generate=true");
+    let public = Arc::new(ServerSettings {
+        public_stats: true,
+        ..(*server_settings()).clone()
+    });
+    for (settings, token, expected) in [
+        (server_settings(), None, StatusCode::UNAUTHORIZED),
+        (server_settings(), Some(key), StatusCode::FORBIDDEN),
+        (public, None, StatusCode::UNAUTHORIZED),
+    ] {
+        let res = get(settings, generate.clone(), token).await;
+        assert_eq!(res.status(), expected, "generating with {token:?}");
+    }
+
+    let res = get(server_settings(), generate.clone(), Some("admin_token")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(aggregation_status(&res).as_deref(), Some("generated"));
+
+    // The cooldown applies per day and scope
+    let res = get(server_settings(), generate.clone(), Some("admin_token")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(aggregation_status(&res).as_deref(), Some("throttled"));
+    let retry_after: u64 = res.headers()[http::header::RETRY_AFTER]
+        .to_str()
+        .expect("header value")
+        .parse()
+        .expect("seconds");
+    assert!((1..=300).contains(&retry_after));
+    let res = get(
+        server_settings(),
+        format!("/aggregated-stats/{day}/generate_test?generate=true"),
+        Some("admin_token"),
+    )
+    .await;
+    assert_eq!(aggregation_status(&res).as_deref(), Some("generated"));
+
+    let res = get(
+        server_settings(),
+        format!("/aggregated-stats/{day}"),
+        Some(key),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(aggregation_status(&res), None);
+
+    // While another replica aggregates the day, the first request waits for it
+    // and the second one waits for the first
+    let mut conn = pool.begin().await.expect("transaction");
+    sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+        .bind(0x6167_6772_i32)
+        .bind(other_day.to_julian_day())
+        .execute(&mut *conn)
+        .await
+        .expect("lock day");
+    let uri = format!("/aggregated-stats/{other_day}?generate=true");
+    let first = tokio::spawn(get(server_settings(), uri.clone(), Some("admin_token")));
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let second = tokio::spawn(get(server_settings(), uri, Some("admin_token")));
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!first.is_finished() && !second.is_finished());
+    conn.commit().await.expect("unlock day");
+
+    let first = first.await.expect("first request");
+    let second = second.await.expect("second request");
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(aggregation_status(&first).as_deref(), Some("generated"));
+    assert_eq!(second.status(), StatusCode::OK);
+    assert_eq!(aggregation_status(&second).as_deref(), Some("joined"));
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_ingest_rules() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let dir = env::temp_dir().join(format!("barad-dur-ingest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("temporary directory");
+    let path = dir.join("ingest-rules.yaml");
+    let mut mtime = std::time::SystemTime::now();
+    let mut write = |rules: &str| {
+        mtime += std::time::Duration::from_secs(10);
+        std::fs::write(&path, rules).expect("write rules");
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(mtime))
+            .expect("set modification time");
+    };
+
+    write(
+        r"
+rules:
+  - name: ingest_test_allow
+    homeserver: ingest-test-allowed.example.com
+    action: allow
+  - name: ingest_test_drop
+    homeserver: ingest-test-drop*
+    action: drop
+  - name: ingest_test_exclude
+    network: 10.42.0.0/16
+    action: exclude
+  - name: ingest_test_reject
+    homeserver: '*.ingest-test.example.com'
+    server_context: ingest_test
+    action: reject
+",
+    );
+    let rules = Arc::new(IngestRules::load(&path).expect("load ingest rules"));
+
+    let (tx, mut rx) = mpsc::channel::<model::Report>(64);
+    let app = server::tests::router(&db_settings)
+        .with_state(db_settings.clone())
+        .layer(Extension(tx))
+        .layer(Extension(rules.clone()))
+        .layer(Extension(server_settings()))
+        .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1337))));
+    let push = |homeserver: &str, server_context: &str, forwarded_for: Option<&str>| {
+        let app = app.clone();
+        let mut request = Request::builder()
+            .method(http::Method::PUT)
+            .uri("/report-usage-stats/push")
+            .header(http::header::CONTENT_TYPE, "application/json");
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("X-Forwarded-For", forwarded_for);
+        }
+        let request = request
+            .body(Body::from(
+                json!({ "homeserver": homeserver, "server_context": server_context }).to_string(),
+            ))
+            .expect("build request");
+        async move { app.oneshot(request).await.unwrap().status() }
+    };
+
+    // Reports matching no rule are stored
+    assert_eq!(
+        push("ingest-test.example.com", "ingest_test", None).await,
+        StatusCode::OK
+    );
+    assert!(!rx.recv().await.expect("stored report").excluded);
+    // Dropped reports are acknowledged, but not stored
+    assert_eq!(
+        push("INGEST-TEST-DROP.example.com", "ingest_test", None).await,
+        StatusCode::OK
+    );
+    assert!(rx.try_recv().is_err());
+    // All conditions of a rule must match
+    assert_eq!(
+        push("a.ingest-test.example.com", "ingest_test", None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        push("a.ingest-test.example.com", "other", None).await,
+        StatusCode::OK
+    );
+    assert!(!rx.recv().await.expect("stored report").excluded);
+    // The forwarded address is the client behind a reverse proxy
+    assert_eq!(
+        push("ingest-test.example.com", "ingest_test", Some("10.42.1.2")).await,
+        StatusCode::OK
+    );
+    assert!(rx.recv().await.expect("stored report").excluded);
+    // Earlier rules win
+    assert_eq!(
+        push(
+            "ingest-test-allowed.example.com",
+            "ingest_test",
+            Some("10.42.1.2")
+        )
+        .await,
+        StatusCode::OK
+    );
+    assert!(!rx.recv().await.expect("stored report").excluded);
+
+    let (status, body) = {
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/ingest-rules")
+                    .header(http::header::AUTHORIZATION, "Bearer admin_token")
+                    .body(Body::empty())
+                    .expect("build request"),
+            )
+            .await
+            .unwrap();
+        let status = res.status();
+        (status, to_bytes(res.into_body(), usize::MAX).await.unwrap())
+    };
+    assert_eq!(status, StatusCode::OK);
+    let hits: Vec<model::IngestRuleHits> = serde_json::from_slice(&body).expect("rules");
+    assert_eq!(
+        hits.iter()
+            .map(|rule| (rule.rule.name.as_str(), rule.hits))
+            .collect::<Vec<_>>(),
+        vec![
+            ("ingest_test_allow", 1),
+            ("ingest_test_drop", 1),
+            ("ingest_test_exclude", 1),
+            ("ingest_test_reject", 1),
+        ]
+    );
+
+    // Excluded reports are stored, but not aggregated
+    let day = time::Date::from_calendar_date(2004, time::Month::January, 1).unwrap();
+    for (homeserver, excluded) in [("ingest_test_0", false), ("ingest_test_1", true)] {
+        let mut report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "daily_active_users": 10,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        report.excluded = excluded;
+        let id = database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+        let saved = database::tests::get_report_by_id(&pool, id)
+            .await
+            .expect("get report");
+        assert_eq!(saved.excluded, excluded);
+    }
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats(&db_settings, day)
+        .await
+        .expect("aggregate stats");
+    let stats = database::get_aggregated_stats(&db_settings, day)
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    assert_eq!(stats.daily_active_homeservers, Some(1));
+    assert_eq!(stats.daily_active_users, Some(10));
+
+    // Changed files are reloaded, broken ones are not
+    let mut seen = None;
+    assert!(rules.reload_if_changed(&path, &mut seen).expect("reload"));
+    assert!(
+        !rules
+            .reload_if_changed(&path, &mut seen)
+            .expect("unchanged")
+    );
+    write(
+        r"
+rules:
+  - name: ingest_test_everything
+    homeserver: '*'
+    action: reject
+",
+    );
+    assert!(rules.reload_if_changed(&path, &mut seen).expect("reload"));
+    assert_eq!(
+        push("ingest-test.example.com", "ingest_test", None).await,
+        StatusCode::FORBIDDEN
+    );
+    for broken in [
+        "rules: [{ name: ingest_test_broken, action: drop }]",
+        "rules: [{ name: ingest_test_broken, network: 10.42.0.0/33, action: drop }]",
+        "rules: [{ name: ingest_test_broken, homeserver: '[', action: drop }]",
+        "rules: [{ name: ingest_test_broken, homeserver: a, action: ignore }]",
+        "rules: [{ name: a, homeserver: a, action: drop }, { name: a, homeserver: b, action: drop }]",
+    ] {
+        write(broken);
+        assert!(rules.reload_if_changed(&path, &mut seen).is_err());
+    }
+    assert_eq!(
+        rules
+            .hits()
+            .iter()
+            .map(|rule| rule.rule.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["ingest_test_everything"]
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_anomaly_detection() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    sqlx::query("DELETE FROM reports WHERE homeserver LIKE 'anomaly_test%'")
+        .execute(&pool)
+        .await
+        .expect("clean up reports");
+    let first_day = time::Date::from_calendar_date(2005, time::Month::January, 1).unwrap();
+    let day = first_day + Duration::days(5);
+    let report = |homeserver: &str, day: time::Date, hour: i64, values: serde_json::Value| {
+        let mut report = json!({
+            "homeserver": homeserver,
+            "server_context": "anomaly_test",
+            "total_users": 200,
+            "daily_active_users": 50,
+            "daily_messages": 1000,
+            "local_timestamp": (day.midnight().assume_utc() + Duration::hours(hour)).unix_timestamp(),
+        });
+        report
+            .as_object_mut()
+            .unwrap()
+            .extend(values.as_object().unwrap().clone());
+        serde_json::from_value::<model::Report>(report).expect("report")
+    };
+    let anomaly = anomaly_settings();
+    let store = async |report: model::Report| {
+        let id = database::tests::store_report(&pool, &report, &anomaly)
+            .await
+            .expect("store report")
+            .expect("stored report");
+        let (score, status): (Option<f64>, Option<String>) =
+            sqlx::query_as("SELECT anomaly_score, review_status FROM reports WHERE id = $1")
+                .bind(id)
+                .fetch_one(&pool)
+                .await
+                .expect("anomaly score");
+        (id, score, status)
+    };
+
+    // Five days of history with some fluctuation
+    for (offset, daily_messages) in [(0, 900), (1, 1000), (2, 1100), (3, 1000), (4, 950)] {
+        let history_day = first_day + Duration::days(offset);
+        store(report(
+            "anomaly_test_0",
+            history_day,
+            12,
+            json!({ "daily_messages": daily_messages }),
+        ))
+        .await;
+        database::refresh_snapshot(&db_settings, &DailyStrategy::default(), history_day)
+            .await
+            .expect("refresh snapshot");
+        database::aggregate_stats(&db_settings, history_day)
+            .await
+            .expect("aggregate stats");
+    }
+
+    let (_, score, status) = store(report(
+        "anomaly_test_0",
+        day,
+        1,
+        json!({ "daily_messages": 1500 }),
+    ))
+    .await;
+    assert!((score.expect("score") - 1.5).abs() < 1e-9);
+    assert_eq!(status, None);
+    let (spike, score, status) = store(report(
+        "anomaly_test_0",
+        day,
+        2,
+        json!({ "daily_messages": 100_000 }),
+    ))
+    .await;
+    assert!((score.expect("score") - 100.0).abs() < 1e-9);
+    assert_eq!(status.as_deref(), Some("flagged"));
+    // Medians are raised to `min_baseline`, and thresholds can differ per metric
+    let (users, score, status) = store(report(
+        "anomaly_test_0",
+        day,
+        3,
+        json!({ "daily_active_users": 600 }),
+    ))
+    .await;
+    assert!((score.expect("score") - 6.0).abs() < 1e-9);
+    assert_eq!(status.as_deref(), Some("flagged"));
+    // Homeservers without enough history aren't scored
+    let (_, score, status) = store(report(
+        "anomaly_test_1",
+        day,
+        1,
+        json!({ "daily_messages": 10 }),
+    ))
+    .await;
+    assert_eq!((score, status), (None, None));
+    let mut excluded = report("anomaly_test_0", day, 4, json!({ "daily_messages": 1 }));
+    excluded.excluded = true;
+    let (_, score, status) = store(excluded).await;
+    assert_eq!((score, status), (None, None));
+
+    // Flagged reports are left out of the aggregation
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats(&db_settings, day)
+        .await
+        .expect("aggregate stats");
+    let stats = database::get_aggregated_stats(&db_settings, day)
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    assert_eq!(stats.daily_messages, Some(1510));
+    let later_day = day + Duration::days(1);
+    database::tests::save_report(&pool, &report("anomaly_test_1", later_day, 1, json!({})))
+        .await
+        .expect("save report");
+    database::refresh_snapshot(&db_settings, &DailyStrategy::default(), later_day)
+        .await
+        .expect("refresh snapshot");
+    database::aggregate_stats(&db_settings, later_day)
+        .await
+        .expect("aggregate stats");
+
+    let app = server::tests::router(&db_settings)
+        .with_state(db_settings.clone())
+        .layer(Extension(aggregation_settings()))
+        .layer(Extension(server_settings()));
+    let request = |method: http::Method, uri: String| {
+        let app = app.clone();
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .header(http::header::AUTHORIZATION, "Bearer admin_token")
+                        .body(Body::empty())
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            let status = res.status();
+            (status, to_bytes(res.into_body(), usize::MAX).await.unwrap())
+        }
+    };
+    let flagged = async || {
+        let (status, body) = request(
+            http::Method::GET,
+            "/admin/flagged-reports?limit=1000".to_owned(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let page: model::Page<model::FlaggedReport> =
+            serde_json::from_slice(&body).expect("flagged reports");
+        page.items
+            .into_iter()
+            .filter(|report| report.server_context.as_deref() == Some("anomaly_test"))
+            .collect::<Vec<_>>()
+    };
+    let reports = flagged().await;
+    assert_eq!(
+        reports.iter().map(|report| report.id).collect::<Vec<_>>(),
+        vec![spike, users]
+    );
+    assert_eq!(
+        reports[0].anomalies,
+        Some(json!({ "daily_messages": 100.0 }))
+    );
+    assert_eq!(
+        reports[1].anomalies,
+        Some(json!({ "daily_active_users": 6.0 }))
+    );
+    assert_eq!(reports[1].review_status, model::ReviewStatus::Flagged);
+
+    // Confirming a report aggregates its day again
+    let (status, body) = request(
+        http::Method::POST,
+        format!("/admin/flagged-reports/{spike}/confirm"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let confirmed: model::FlaggedReport = serde_json::from_slice(&body).expect("report");
+    assert_eq!(confirmed.review_status, model::ReviewStatus::Confirmed);
+    assert!(confirmed.reviewed_at.is_some());
+    let stats = database::get_aggregated_stats(&db_settings, day)
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    assert_eq!(stats.daily_messages, Some(100_010));
+    // and refreshes the running totals of the days after it
+    let later_stats = database::get_aggregated_stats(&db_settings, later_day)
+        .await
+        .expect("get aggregated stats")
+        .expect("aggregated stats");
+    assert_eq!(
+        later_stats.total_messages,
+        stats.total_messages.map(|total| total + 1000)
+    );
+
+    let (status, _) = request(
+        http::Method::POST,
+        format!("/admin/flagged-reports/{users}/reject"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(
+        http::Method::POST,
+        format!("/admin/flagged-reports/{users}/confirm"),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(flagged().await, vec![]);
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_cors() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let dashboard = "https://dashboard.example.com";
+    let cors = CorsSettings {
+        push: Some(CorsPolicy {
+            allowed_origins: vec!["*".to_owned()],
+            allowed_methods: vec!["PUT".to_owned()],
+            allowed_headers: vec!["content-type".to_owned()],
+            allow_credentials: false,
+            max_age_seconds: None,
+        }),
+        stats: Some(CorsPolicy {
+            allowed_origins: vec![dashboard.to_owned()],
+            allowed_methods: vec!["GET".to_owned()],
+            allowed_headers: vec!["authorization".to_owned()],
+            allow_credentials: true,
+            max_age_seconds: Some(600),
+        }),
+        admin: None,
+    };
+    let (tx, _rx) = mpsc::channel::<model::Report>(64);
+    let app = server::tests::router_with_cors(&db_settings, &cors)
+        .expect("router")
+        .with_state(db_settings.clone())
+        .layer(Extension(tx))
+        .layer(Extension(Arc::new(IngestRules::default())))
+        .layer(Extension(aggregation_settings()))
+        .layer(Extension(server_settings()))
+        .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1337))));
+    let request = |method: http::Method, uri: &str, origin: &str, headers: &[(&str, &str)]| {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::ORIGIN, origin);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(Body::empty()).expect("build request");
+        let app = app.clone();
+        async move {
+            let res = app.oneshot(request).await.unwrap();
+            let header = |name: http::HeaderName| {
+                res.headers()
+                    .get(name)
+                    .map(|value| value.to_str().unwrap().to_owned())
+            };
+            (
+                res.status(),
+                header(http::header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                header(http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+                header(http::header::ACCESS_CONTROL_MAX_AGE),
+            )
+        }
+    };
+    let preflight = |uri: &'static str, origin: &'static str, method: &'static str| {
+        request(
+            http::Method::OPTIONS,
+            uri,
+            origin,
+            &[
+                ("Access-Control-Request-Method", method),
+                ("Access-Control-Request-Headers", "authorization"),
+            ],
+        )
+    };
+
+    // Preflight requests don't need an API key
+    assert_eq!(
+        preflight("/contexts", dashboard, "GET").await,
+        (
+            StatusCode::OK,
+            Some(dashboard.to_owned()),
+            Some("true".to_owned()),
+            Some("600".to_owned())
+        )
+    );
+    assert_eq!(
+        request(
+            http::Method::GET,
+            "/contexts",
+            dashboard,
+            &[("Authorization", "Bearer admin_token")]
+        )
+        .await,
+        (
+            StatusCode::OK,
+            Some(dashboard.to_owned()),
+            Some("true".to_owned()),
+            None
+        )
+    );
+    let (_, origin, _, _) = preflight("/contexts", "https://evil.example.com", "GET").await;
+    assert_eq!(origin, None);
+
+    // Route groups have their own policies
+    let (status, origin, credentials, _) = preflight(
+        "/report-usage-stats/push",
+        "https://anywhere.example.com",
+        "PUT",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(origin.as_deref(), Some("*"));
+    assert_eq!(credentials, None);
+    let (_, origin, _, _) = preflight("/admin/homeservers", dashboard, "GET").await;
+    assert_eq!(origin, None);
+    let (_, origin, _, _) = request(
+        http::Method::GET,
+        "/health",
+        dashboard,
+        &[("Authorization", "Bearer admin_token")],
+    )
+    .await;
+    assert_eq!(origin, None);
+
+    let invalid = CorsSettings {
+        admin: Some(CorsPolicy {
+            allowed_origins: vec!["*".to_owned()],
+            allowed_methods: vec!["GET".to_owned()],
+            allowed_headers: vec![],
+            allow_credentials: true,
+            max_age_seconds: None,
+        }),
+        ..CorsSettings::default()
+    };
+    assert!(server::tests::router_with_cors(&db_settings, &invalid).is_err());
+    let invalid = CorsSettings {
+        stats: Some(CorsPolicy {
+            allowed_origins: vec![dashboard.to_owned()],
+            allowed_methods: vec!["G E T".to_owned()],
+            allowed_headers: vec![],
+            allow_credentials: false,
+            max_age_seconds: None,
+        }),
+        ..CorsSettings::default()
+    };
+    assert!(server::tests::router_with_cors(&db_settings, &invalid).is_err());
+}
+
+#[tokio::test]
+async fn test_admin_listener() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let (tx, _rx) = mpsc::channel::<model::Report>(64);
+    let app = |router: Router<Arc<DBSettings>>, settings: Arc<ServerSettings>| {
+        router
+            .with_state(db_settings.clone())
+            .layer(Extension(tx.clone()))
+            .layer(Extension(Arc::new(IngestRules::default())))
+            .layer(Extension(aggregation_settings()))
+            .layer(Extension(settings))
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1337))))
+    };
+    let status = async |app: &Router, method: http::Method, uri: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::AUTHORIZATION, "Bearer admin_token")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"homeserver": "admin_listener_test"}"#))
+            .expect("build request");
+        app.clone().oneshot(request).await.unwrap().status()
+    };
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = time::Date::from_calendar_date(2006, time::Month::January, 1).unwrap();
+    let report: model::Report = serde_json::from_value(json!({
+        "homeserver": "admin_listener_test",
+        "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+    }))
+    .expect("report");
+    database::tests::save_report(&pool, &report)
+        .await
+        .expect("save report");
+    let generate = "/aggregated-stats/2006-01-01?generate=true";
+
+    // Without an admin listener, everything is served on `host`
+    let (public, internal) =
+        server::tests::listener_routers(&db_settings, &server_settings()).expect("routers");
+    assert!(internal.is_none());
+    let public = app(public, server_settings());
+    for uri in ["/health", "/admin/homeservers", "/openapi.json", generate] {
+        assert_eq!(
+            status(&public, http::Method::GET, uri).await,
+            StatusCode::OK
+        );
+    }
+
+    for stats_on_host in [true, false] {
+        let settings = Arc::new(ServerSettings {
+            admin_host: Some("127.0.0.1:9090".to_owned()),
+            stats_on_host,
+            ..(*server_settings()).clone()
+        });
+        let (public, internal) =
+            server::tests::listener_routers(&db_settings, &settings).expect("routers");
+        let public = app(public, settings.clone());
+        let internal = app(internal.expect("admin listener"), settings);
+
+        assert_eq!(
+            status(&public, http::Method::PUT, "/report-usage-stats/push").await,
+            StatusCode::OK
+        );
+        for uri in ["/health", "/metrics", "/admin/homeservers", "/openapi.json"] {
+            assert_eq!(
+                status(&public, http::Method::GET, uri).await,
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(
+                status(&internal, http::Method::GET, uri).await,
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            status(&internal, http::Method::PUT, "/report-usage-stats/push").await,
+            StatusCode::NOT_FOUND
+        );
+
+        // On-demand aggregation is only available on the admin listener
+        let (contexts, generate_public) = if stats_on_host {
+            (StatusCode::OK, StatusCode::FORBIDDEN)
+        } else {
+            (StatusCode::NOT_FOUND, StatusCode::NOT_FOUND)
+        };
+        assert_eq!(
+            status(&public, http::Method::GET, "/contexts").await,
+            contexts
+        );
+        assert_eq!(
+            status(&public, http::Method::GET, generate).await,
+            generate_public
+        );
+        assert_eq!(
+            status(&internal, http::Method::GET, "/contexts").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&internal, http::Method::GET, generate).await,
+            StatusCode::OK
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_tls_reload() {
+    let dir = env::temp_dir().join(format!("barad-dur-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("temporary directory");
+    let settings = crate::settings::TlsSettings {
+        cert_path: dir.join("cert.pem"),
+        key_path: dir.join("key.pem"),
+        reload_seconds: 60,
+        http_host: None,
+    };
+    // Renewals within the same second must be noticed too
+    let mut mtime = std::time::SystemTime::now();
+    let mut write = |cert: &str, key: &str| {
+        mtime += std::time::Duration::from_secs(10);
+        for (path, content) in [(&settings.cert_path, cert), (&settings.key_path, key)] {
+            std::fs::write(path, content).expect("write PEM file");
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .and_then(|file| file.set_modified(mtime))
+                .expect("set modification time");
+        }
+    };
+    let certificate = || {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+            .expect("self-signed certificate");
+        (certified.cert.pem(), certified.key_pair.serialize_pem())
+    };
+
+    let (cert, key) = certificate();
+    write(&cert, &key);
+    let config = crate::tls::load(&settings).await.expect("load TLS config");
+    let mut seen = None;
+    assert!(
+        crate::tls::reload_if_changed(&config, &settings, &mut seen)
+            .await
+            .expect("first check")
+    );
+    let loaded = config.get_inner();
+    assert!(
+        !crate::tls::reload_if_changed(&config, &settings, &mut seen)
+            .await
+            .expect("unchanged files")
+    );
+    assert!(Arc::ptr_eq(&loaded, &config.get_inner()));
+
+    let (cert, key) = certificate();
+    write(&cert, &key);
+    assert!(
+        crate::tls::reload_if_changed(&config, &settings, &mut seen)
+            .await
+            .expect("renewed certificate")
+    );
+    assert!(!Arc::ptr_eq(&loaded, &config.get_inner()));
+
+    // A broken renewal keeps the current certificate
+    let loaded = config.get_inner();
+    write("not a certificate", &key);
+    assert!(
+        crate::tls::reload_if_changed(&config, &settings, &mut seen)
+            .await
+            .is_err()
+    );
+    assert!(Arc::ptr_eq(&loaded, &config.get_inner()));
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// The committed OpenAPI document must match the one generated from the
+/// routes, run with `UPDATE_OPENAPI=1` to regenerate it
+#[test]
+fn test_openapi_up_to_date() {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("docs/openapi.json");
+    let spec = server::tests::openapi()
+        .to_pretty_json()
+        .expect("OpenAPI document")
+        + "\n";
+
+    if env::var_os("UPDATE_OPENAPI").is_some() {
+        std::fs::create_dir_all(path.parent().expect("docs directory")).expect("docs directory");
+        std::fs::write(&path, &spec).expect("write OpenAPI document");
+        return;
+    }
+
+    let committed = std::fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        committed == spec,
+        "{} is out of date with the routes and models, regenerate it with \
+         `UPDATE_OPENAPI=1 cargo test test_openapi_up_to_date`",
+        path.display(),
+    );
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_api_keys() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+
+    for (key, scopes) in [
+        ("api_keys_test_global", vec!["stats:global"]),
+        ("api_keys_test_context", vec!["stats:context:acme corp"]),
+        ("api_keys_test_admin", vec!["admin"]),
+        ("api_keys_test_invalid", vec!["stats:everything"]),
+    ] {
+        sqlx::query(
+            "INSERT INTO api_keys (label, key_hash, scopes) VALUES ($1, $2, $3)
+             ON CONFLICT (key_hash) DO NOTHING",
+        )
+        .bind(key)
+        .bind(crate::auth::hash_key(key))
+        .bind(scopes)
+        .execute(&pool)
+        .await
+        .expect("insert API key");
+    }
+    // Keys can be created by hand in the database
+    sqlx::query(
+        "INSERT INTO api_keys (label, key_hash, scopes)
+         VALUES ('psql', sha256('api_keys_test_psql'), '{stats:global}')
+         ON CONFLICT (key_hash) DO NOTHING",
+    )
+    .execute(&pool)
+    .await
+    .expect("insert API key");
+
+    let (tx, mut rx) = mpsc::channel::<model::Report>(8);
+    let app = |settings: Arc<ServerSettings>| {
+        server::tests::router(&db_settings)
+            .with_state(db_settings.clone())
+            .layer(Extension(tx.clone()))
+            .layer(Extension(Arc::new(IngestRules::default())))
+            .layer(Extension(aggregation_settings()))
+            .layer(Extension(settings))
+            .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 8080))))
+    };
+    let status = |settings: Arc<ServerSettings>, uri: &'static str, key: Option<&'static str>| {
+        let app = app(settings);
+        async move {
+            let mut request = Request::builder().uri(uri);
+            if let Some(key) = key {
+                request = request.header(http::header::AUTHORIZATION, format!("Bearer {key}"));
+            }
+            app.oneshot(request.body(Body::empty()).expect("build request"))
+                .await
+                .unwrap()
+                .status()
+        }
+    };
+
+    let global = "/contexts";
+    let context = "/contexts/acme%20corp/churn?from=2003-01-01&to=2003-01-02";
+    let other_context = "/contexts/other/churn?from=2003-01-01&to=2003-01-02";
+    let admin = "/admin/homeservers";
+    for (uri, key, expected) in [
+        (global, None, StatusCode::UNAUTHORIZED),
+        (
+            global,
+            Some("api_keys_test_unknown"),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (global, Some("api_keys_test_global"), StatusCode::OK),
+        (global, Some("api_keys_test_psql"), StatusCode::OK),
+        (global, Some("api_keys_test_context"), StatusCode::FORBIDDEN),
+        (global, Some("api_keys_test_admin"), StatusCode::OK),
+        (global, Some("api_keys_test_invalid"), StatusCode::FORBIDDEN),
+        (global, Some("admin_token"), StatusCode::OK),
+        (context, None, StatusCode::UNAUTHORIZED),
+        (context, Some("api_keys_test_global"), StatusCode::FORBIDDEN),
+        (context, Some("api_keys_test_context"), StatusCode::OK),
+        (context, Some("api_keys_test_admin"), StatusCode::OK),
+        (
+            other_context,
+            Some("api_keys_test_context"),
+            StatusCode::FORBIDDEN,
+        ),
+        (admin, Some("api_keys_test_global"), StatusCode::FORBIDDEN),
+        (admin, Some("api_keys_test_admin"), StatusCode::OK),
+        (admin, Some("admin_token"), StatusCode::OK),
+        ("/health", None, StatusCode::OK),
+    ] {
+        assert_eq!(
+            status(server_settings(), uri, key).await,
+            expected,
+            "testing GET '{uri}' with {key:?}"
+        );
+    }
+
+    let public = Arc::new(ServerSettings {
+        public_stats: true,
+        ..(*server_settings()).clone()
+    });
+    assert_eq!(status(public.clone(), global, None).await, StatusCode::OK);
+    assert_eq!(status(public.clone(), context, None).await, StatusCode::OK);
+    assert_eq!(status(public, admin, None).await, StatusCode::UNAUTHORIZED);
+
+    // Reports can still be pushed without any key
+    let res = app(server_settings())
+        .oneshot(
+            Request::builder()
+                .method(http::Method::PUT)
+                .uri("/report-usage-stats/push")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"homeserver": "api_keys_test"}"#))
+                .expect("build request"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        rx.recv().await.expect("report").homeserver.as_deref(),
+        Some("api_keys_test")
+    );
+}
+
+/// Subscriber collecting the fields of every span, to check what ends up in
+/// traces
+#[derive(Debug, Clone, Default)]
+struct SpanFields(Arc<std::sync::Mutex<String>>);
+
+impl SpanFields {
+    fn collect(&self, record: impl FnOnce(&mut dyn tracing::field::Visit)) {
+        let mut fields = self.0.lock().unwrap();
+        record(
+            &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                fields.push_str(&format!("{field}={value:?}\n"));
+            },
+        );
+    }
+}
+
+impl tracing::Subscriber for SpanFields {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        self.collect(|visit| span.record(visit));
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+        self.collect(|visit| values.record(visit));
+    }
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event<'_>) {}
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[tokio::test]
+async fn test_bearer_token_not_traced() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let token = "span_test_secret";
+    let app = server::tests::router(&db_settings)
+        .with_state(db_settings.clone())
+        .layer(Extension(aggregation_settings()))
+        .layer(Extension(Arc::new(ServerSettings {
+            admin_token: Some(token.to_owned()),
+            ..(*server_settings()).clone()
+        })));
+
+    let spans = SpanFields::default();
+    let _guard = tracing::subscriber::set_default(spans.clone());
+    for uri in [
+        "/aggregated-stats/2002-01-01",
+        "/aggregated-stats/2002-01-01/daily_test",
+        "/aggregated-stats?from=2002-01-01&to=2002-01-02",
+        "/aggregated-stats-by-context/daily_test?from=2002-01-01&to=2002-01-02",
+    ] {
+        app.clone()
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .expect("build request"),
+            )
+            .await
+            .unwrap();
+    }
+
+    let fields = spans.0.lock().unwrap().clone();
+    assert!(fields.contains("params="), "no span fields in {fields}");
+    assert!(!fields.contains(token), "bearer token traced in {fields}");
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_audit_log() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let started = sqlx::query_scalar::<_, time::OffsetDateTime>("SELECT now()")
+        .fetch_one(&pool)
+        .await
+        .expect("now");
+    let key = "audit_log_test_admin";
+    let key_id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO api_keys (label, key_hash, scopes) VALUES ($1, $2, '{admin}')
+         ON CONFLICT (key_hash) DO UPDATE SET label = EXCLUDED.label
+         RETURNING id",
+    )
+    .bind(key)
+    .bind(crate::auth::hash_key(key))
+    .fetch_one(&pool)
+    .await
+    .expect("insert API key");
+    let day = time::Date::from_calendar_date(2007, time::Month::January, 1).unwrap();
+    let report: model::Report = serde_json::from_value(json!({
+        "homeserver": "audit_log_test",
+        "daily_active_users": 5,
+        "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+    }))
+    .expect("report");
+    database::tests::save_report(&pool, &report)
+        .await
+        .expect("save report");
+    sqlx::query("DELETE FROM aggregation_runs WHERE day = $1")
+        .bind(day)
+        .execute(&pool)
+        .await
+        .expect("delete aggregation runs");
+
+    let app = server::tests::router(&db_settings)
+        .with_state(db_settings.clone())
+        .layer(Extension(aggregation_settings()))
+        .layer(Extension(server_settings()));
+    let request =
+        |method: http::Method, uri: String, token: &str, body: Option<serde_json::Value>| {
+            let app = app.clone();
+            let token = token.to_owned();
+            async move {
+                let request = Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+                let request = match body {
+                    Some(body) => request
+                        .header(http::header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(body.to_string())),
+                    None => request.body(Body::empty()),
+                };
+                let res = app.oneshot(request.expect("build request")).await.unwrap();
+                let status = res.status();
+                let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+                (status, body)
+            }
+        };
+    let audit_log = |query: String| {
+        let request = request(
+            http::Method::GET,
+            format!("/admin/audit-log?{query}"),
+            "admin_token",
+            None,
+        );
+        async move {
+            let (status, body) = request.await;
+            assert_eq!(status, StatusCode::OK, "testing audit log with {query}");
+            serde_json::from_slice::<model::Page<model::AuditEntry>>(&body).expect("audit log")
+        }
+    };
+
+    let (status, body) = request(
+        http::Method::POST,
+        "/admin/keys".to_owned(),
+        key,
+        Some(json!({ "label": "audit_log_test", "scopes": ["stats:global"] })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let created: model::ApiKeySecret = serde_json::from_slice(&body).expect("API key");
+    let (status, _) = request(
+        http::Method::DELETE,
+        format!("/admin/keys/{}", created.key.id),
+        key,
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = request(http::Method::DELETE, "/admin/keys/-1".to_owned(), key, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = request(
+        http::Method::GET,
+        "/aggregated-stats/2007-01-01?generate=true".to_owned(),
+        key,
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    // Reads and refused requests aren't administrative actions
+    request(http::Method::GET, "/admin/keys".to_owned(), key, None).await;
+    request(
+        http::Method::DELETE,
+        "/admin/keys/-1".to_owned(),
+        &created.secret,
+        None,
+    )
+    .await;
+
+    let entries = audit_log(format!("actor={key}&limit=100")).await;
+    let actions = entries
+        .items
+        .iter()
+        .filter(|entry| entry.created_at >= started)
+        .map(|entry| {
+            assert_eq!(entry.api_key_id, Some(key_id));
+            (
+                entry.action.as_str(),
+                entry.parameters.clone(),
+                entry.outcome,
+                entry.error.as_deref(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        actions,
+        vec![
+            (
+                "aggregation.generate",
+                json!({ "scope": "global", "day": "2007-01-01" }),
+                model::AuditOutcome::Succeeded,
+                None
+            ),
+            (
+                "api_key.revoke",
+                json!({ "id": -1 }),
+                model::AuditOutcome::Failed,
+                Some("no API key with ID -1")
+            ),
+            (
+                "api_key.revoke",
+                json!({ "id": created.key.id }),
+                model::AuditOutcome::Succeeded,
+                None
+            ),
+            (
+                "api_key.create",
+                json!({ "label": "audit_log_test", "scopes": ["stats:global"], "expires_at": null }),
+                model::AuditOutcome::Succeeded,
+                None
+            ),
+        ]
+    );
+
+    // The secret of the created key is never recorded
+    let entries = audit_log("action=api_key.create&limit=100".to_owned()).await;
+    assert!(
+        entries
+            .items
+            .iter()
+            .all(|entry| !entry.parameters.to_string().contains(&created.secret))
+    );
+
+    let since = started
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap();
+    let entries = audit_log(format!(
+        "actor={key}&outcome=failed&since={}",
+        since.replace('+', "%2B")
+    ))
+    .await;
+    assert_eq!(entries.total, 1);
+    assert_eq!(entries.items[0].action, "api_key.revoke");
+    let entries = audit_log(format!("actor={key}&until=2001-01-01T00:00:00Z")).await;
+    assert_eq!(entries.total, 0);
+    let (status, _) = request(
+        http::Method::GET,
+        "/admin/audit-log?since=yesterday".to_owned(),
+        "admin_token",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+#[allow(clippy::too_many_lines)]
+async fn test_homeserver_erasure() {
+    let db_settings = Arc::new(DBSettings {
+        url: env::var("DATABASE_URL").expect("database URL"),
+    });
+    let pool = sqlx::PgPool::connect(&db_settings.url)
+        .await
+        .expect("DB connection");
+    let day = |day| time::Date::from_calendar_date(2008, time::Month::January, day).unwrap();
+    for (homeserver, server_context, day) in [
+        ("erasure_test", "erasure_test_a", day(1)),
+        ("erasure_test", "erasure_test_b", day(2)),
+        ("erasure_test", "erasure_test_a", day(3)),
+        ("erasure_test_other", "erasure_test_a", day(1)),
+        ("erasure_test_other", "erasure_test_a", day(2)),
+    ] {
+        let report: model::Report = serde_json::from_value(json!({
+            "homeserver": homeserver,
+            "server_context": server_context,
+            "daily_active_users": 5,
+            "daily_messages": 10,
+            "local_timestamp": day.midnight().assume_utc().unix_timestamp(),
+        }))
+        .expect("report");
+        database::tests::save_report(&pool, &report)
+            .await
+            .expect("save report");
+    }
+    for day in [day(1), day(2), day(3)] {
+        database::aggregate_day(&db_settings, &DailyStrategy::default(), day)
+            .await
+            .expect("aggregate day");
+    }
+    // The homeserver is active on the 3rd and churns 30 days later
+    let churned = day(3) + Duration::days(30);
+    for day in [day(3), churned] {
+        database::aggregate_churn(&db_settings, 30, day)
+            .await
+            .expect("aggregate churn");
+    }
+    let churn = async |day| {
+        sqlx::query_as::<_, (i64, i64)>(
+            "SELECT active_homeservers, churned_homeservers FROM homeserver_churn WHERE day = $1",
+        )
+        .bind(day)
+        .fetch_one(&pool)
+        .await
+        .expect("churn")
+    };
+    assert_eq!(churn(day(3)).await, (1, 0));
+    assert_eq!(churn(churned).await, (0, 1));
+
+    let erase = |aggregation: Arc<AggregationSettings>, body: serde_json::Value| {
+        let app = server::tests::router(&db_settings)
+            .with_state(db_settings.clone())
+            .layer(Extension(aggregation))
+            .layer(Extension(server_settings()));
+        async move {
+            let res = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::POST)
+                        .uri("/admin/homeservers/erasure_test/erase")
+                        .header(http::header::AUTHORIZATION, "Bearer admin_token")
+                        .header(http::header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(body.to_string()))
+                        .expect("build request"),
+                )
+                .await
+                .unwrap();
+            let status = res.status();
+            let body = to_bytes(res.into_body(), usize::MAX).await.expect("body");
+            (status, body)
+        }
+    };
+    let stats = async |day| {
+        database::get_aggregated_stats(&db_settings, day)
+            .await
+            .expect("aggregated stats")
+            .map(|stats| {
+                (
+                    stats.daily_active_homeservers,
+                    stats.daily_messages,
+                    stats.total_messages.unwrap_or_default(),
+                )
+            })
+    };
+    let context_stats = async |day, server_context: &str| {
+        database::get_aggregated_stats_by_context(&db_settings, day, server_context.to_owned())
+            .await
+            .expect("aggregated stats by context")
+            .map(|stats| stats.daily_active_homeservers)
+    };
+    // Message totals run over all days of the other tests too
+    let (_, _, total) = stats(day(2)).await.expect("stats");
+
+    let (status, _) = erase(
+        aggregation_settings(),
+        json!({ "from": "2008-01-03", "to": "2008-01-02" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = erase(
+        aggregation_settings(),
+        json!({ "from": "2008-01-02", "to": "2008-01-03" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let erasure: model::Erasure = serde_json::from_slice(&body).expect("erasure");
+    assert_eq!(erasure.reports_deleted, 2);
+    assert_eq!(erasure.days, vec![day(2), day(3)]);
+    assert_eq!(
+        erasure.server_contexts,
+        vec!["erasure_test_a".to_owned(), "erasure_test_b".to_owned()]
+    );
+    // Days and server contexts without any reports left lose their stats
+    assert_eq!(stats(day(2)).await, Some((Some(1), Some(10), total - 10)));
+    assert_eq!(stats(day(3)).await, None);
+    assert_eq!(context_stats(day(2), "erasure_test_a").await, Some(Some(1)));
+    assert_eq!(context_stats(day(2), "erasure_test_b").await, None);
+    // Churn too, and nothing is left for the scheduled jobs
+    assert_eq!(churn(day(3)).await, (0, 0));
+    assert_eq!(churn(churned).await, (0, 0));
+    let pending = database::pending_aggregations(&db_settings)
+        .await
+        .expect("pending aggregations");
+    assert!(!pending.iter().any(|pending| erasure.days.contains(pending)));
+
+    let (status, body) = erase(aggregation_settings(), json!({ "refuse_reports": true })).await;
+    assert_eq!(status, StatusCode::OK);
+    let erasure: model::Erasure = serde_json::from_slice(&body).expect("erasure");
+    assert_eq!(erasure.reports_deleted, 1);
+    assert_eq!(erasure.days, vec![day(1)]);
+    assert!(erasure.refuse_reports);
+    // The running message totals of the later days no longer count it either
+    assert_eq!(
+        stats(day(1)).await.map(|(active, ..)| active),
+        Some(Some(1))
+    );
+    assert_eq!(stats(day(2)).await, Some((Some(1), Some(10), total - 20)));
+    let remaining = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM reports WHERE homeserver = 'erasure_test'",
+    )
+    .fetch_one(&pool)
+    .await
+    .expect("count reports");
+    assert_eq!(remaining, 0);
+
+    // Days failing to aggregate are left to the scheduled jobs
+    let report: model::Report = serde_json::from_value(json!({
+        "homeserver": "erasure_test",
+        "local_timestamp": day(3).midnight().assume_utc().unix_timestamp(),
+    }))
+    .expect("report");
+    database::tests::save_report(&pool, &report)
+        .await
+        .expect("save report");
+    let failing = Arc::new(AggregationSettings {
+        churn_days: i64::from(i32::MAX) + 1,
+        ..(*aggregation_settings()).clone()
+    });
+    let (status, body) = erase(failing, json!({ "refuse_reports": true })).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let erasure: model::Erasure = serde_json::from_slice(&body).expect("erasure");
+    assert_eq!(erasure.reports_deleted, 1);
+    assert_eq!(erasure.pending_days, vec![day(3)]);
+    let pending = database::pending_aggregations(&db_settings)
+        .await
+        .expect("pending aggregations");
+    assert!(pending.contains(&day(3)));
+    database::aggregate_pending(&db_settings, &aggregation_settings(), &pending)
+        .await
+        .expect("aggregate pending days");
+    let pending = database::pending_aggregations(&db_settings)
+        .await
+        .expect("pending aggregations");
+    assert!(!pending.contains(&day(3)));
+
+    // Its reports are refused from then on, unlike those of other homeservers
+    for (homeserver, stored) in [("erasure_test", false), ("erasure_test_other", true)] {
+        let report: model::Report =
+            serde_json::from_value(json!({ "homeserver": homeserver })).expect("report");
+        let id = database::tests::store_report(&pool, &report, &anomaly_settings())
+            .await
+            .expect("store report");
+        assert_eq!(id.is_some(), stored, "storing a report of {homeserver}");
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use log::{info, warn};
+
+use crate::settings::TlsSettings;
+
+/// Loads the certificate chain and key, using the ring crypto provider like
+/// the database connections
+pub async fn load(settings: &TlsSettings) -> Result<RustlsConfig> {
+    // Fails if already installed, which is fine
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    RustlsConfig::from_pem_file(&settings.cert_path, &settings.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "failed loading TLS certificate {} and key {}",
+                settings.cert_path.display(),
+                settings.key_path.display()
+            )
+        })
+}
+
+/// Modification times of the certificate chain and key
+async fn modified(settings: &TlsSettings) -> Result<(SystemTime, SystemTime)> {
+    let modified = async |path: &std::path::Path| {
+        tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("can't stat {}", path.display()))
+    };
+    Ok((
+        modified(&settings.cert_path).await?,
+        modified(&settings.key_path).await?,
+    ))
+}
+
+/// Reloads the certificate chain and key if either file changed since `seen`,
+/// keeping the current ones if the new files are invalid. Returns whether
+/// they were reloaded.
+pub async fn reload_if_changed(
+    config: &RustlsConfig,
+    settings: &TlsSettings,
+    seen: &mut Option<(SystemTime, SystemTime)>,
+) -> Result<bool> {
+    let modified = modified(settings).await?;
+    if *seen == Some(modified) {
+        return Ok(false);
+    }
+
+    config
+        .reload_from_pem_file(&settings.cert_path, &settings.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "failed reloading TLS certificate {}, keeping the current one",
+                settings.cert_path.display()
+            )
+        })?;
+    *seen = Some(modified);
+    Ok(true)
+}
+
+/// Checks the certificate chain and key for changes every
+/// `tls.reload_seconds`, so that renewed certificates are picked up without
+/// a restart
+pub async fn watch(config: RustlsConfig, settings: TlsSettings) {
+    let mut seen = modified(&settings).await.ok();
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.reload_seconds.max(1)));
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        match reload_if_changed(&config, &settings, &mut seen).await {
+            Ok(true) => info!("Reloaded TLS certificate {}", settings.cert_path.display()),
+            Ok(false) => {}
+            Err(err) => warn!("{err:?}"),
+        }
+    }
+}